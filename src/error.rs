@@ -0,0 +1,56 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum TextError {
+    OutOfBounds(ropey::Error),
+    InvalidAddr(String),
+    Regex(String),
+    NoMatch(String),
+    Io(io::Error),
+    /// Input that isn't UTF-8, with the offset of the first bad byte when
+    /// it is known.
+    InvalidUtf8 {
+        byte_offset: Option<usize>,
+    },
+    ReadOnly,
+}
+
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TextError::OutOfBounds(e) => write!(f, "{}", e),
+            TextError::InvalidAddr(addr) => write!(f, "invalid address: {}", addr),
+            TextError::Regex(e) => write!(f, "invalid regex: {}", e),
+            TextError::NoMatch(pattern) => write!(f, "no match for /{}/", pattern),
+            TextError::Io(e) => write!(f, "{}", e),
+            TextError::InvalidUtf8 {
+                byte_offset: Some(offset),
+            } => write!(f, "invalid utf-8 at byte {}", offset),
+            TextError::InvalidUtf8 { byte_offset: None } => write!(f, "invalid utf-8"),
+            TextError::ReadOnly => write!(f, "buffer is read-only"),
+        }
+    }
+}
+
+impl std::error::Error for TextError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TextError::OutOfBounds(e) => Some(e),
+            TextError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ropey::Error> for TextError {
+    fn from(e: ropey::Error) -> Self {
+        TextError::OutOfBounds(e)
+    }
+}
+
+impl From<io::Error> for TextError {
+    fn from(e: io::Error) -> Self {
+        TextError::Io(e)
+    }
+}
@@ -1,3 +1,6 @@
+// 9P file server, not wired up yet.
+#![allow(unused)]
+
 use ninep::{
     Result,
     fs::{FileMeta, IoUnit, Mode, Perm, Stat},
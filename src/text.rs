@@ -1,261 +1,5545 @@
-use ropey::{Rope, RopeSlice};
+use crate::error::TextError;
+use regex_cursor::Input;
+use regex_cursor::engines::meta::Regex;
+use regex_cursor::regex_automata::util::{interpolate, syntax};
+use ropey::{Rope, RopeBuilder, RopeSlice};
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::fmt;
 use std::fs::File;
-use std::io::{self, BufReader};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::ops::Range;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::str::FromStr;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::{
+    Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+};
+use unicode_segmentation::{GraphemeCursor, GraphemeIncomplete, UnicodeSegmentation};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Debug)]
 pub enum Addr {
     Index(usize),
     Coordinates(usize, usize),
     LineStart(usize),
+    /// The line's first char past its indentation, like vi's `^`, or its
+    /// start when the line is blank.
+    LineStartNonBlank(usize),
+    /// Start of the next line: a selection ending here keeps the newline.
     LineEnd(usize),
+    /// The line's newline itself: a selection ending here leaves it out.
+    LineEndBeforeNewline(usize),
     BufferStart,
     BufferEnd,
+    Regex(String),
+    /// The start of the last match ending at or before the start of dot,
+    /// like sam's `?re?`. Resolved without a dot, it searches back from the
+    /// buffer end.
+    RegexBackward(String),
+    /// Counts forward from the end of dot, or back from its start when
+    /// negative, like sam's `+#n` and `-#n`. Resolved without a dot, it is
+    /// relative to the buffer start.
+    Relative(isize),
+    /// Wherever the mark currently is.
+    Mark(Mark),
+    /// A byte offset, as spoken by LSP and most external tools. An offset
+    /// inside a multi-byte char is an error.
+    Byte(usize),
+    /// Line and UTF-16 column, as LSP positions are. A column landing
+    /// between the halves of a surrogate pair is an error.
+    Utf16(usize, usize),
+    /// The line start nearest to that share of the buffer, like less's
+    /// `50%`. 0 is the buffer start and 100, or more, the start of the last
+    /// line.
+    Percent(u8),
+}
+
+// A rope is never left half edited, so a lock poisoned by a panic in some
+// other thread is taken over rather than turned into a panic on every later
+// call. A with_lock_mut closure that panics keeps whatever it changed.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// The rope sits behind a RwLock so readers, such as a UI thread drawing,
+// only wait on edits and not on each other.
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn compile(pattern: &str) -> Result<Regex, TextError> {
+    compile_with(pattern, SearchOptions::default())
+}
+
+fn compile_with(pattern: &str, options: SearchOptions) -> Result<Regex, TextError> {
+    let syntax = syntax::Config::new()
+        .case_insensitive(options.case_insensitive)
+        .multi_line(options.multi_line)
+        .dot_matches_new_line(options.dot_matches_newline);
+    Regex::builder()
+        .syntax(syntax)
+        .build(pattern)
+        .map_err(|e| TextError::Regex(e.to_string()))
+}
+
+/// The flags a pattern is compiled with, as its inline (?i), (?m) and (?s)
+/// would set them. The pattern can still turn them back off inline.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SearchOptions {
+    pub case_insensitive: bool,
+    pub multi_line: bool,
+    pub dot_matches_newline: bool,
+}
+
+const REGEX_CACHE_LEN: usize = 16;
+
+// The patterns a buffer last searched for, most recent first, so an
+// incremental search doesn't recompile its pattern on every keystroke.
+#[derive(Default)]
+struct RegexCache {
+    entries: VecDeque<(String, SearchOptions, Regex)>,
+    #[cfg(test)]
+    compiled: usize,
+}
+
+impl RegexCache {
+    fn get(&mut self, pattern: &str, options: SearchOptions) -> Result<Regex, TextError> {
+        let hit = self
+            .entries
+            .iter()
+            .position(|(p, o, _)| p == pattern && *o == options);
+        let entry = match hit {
+            Some(i) => self.entries.remove(i).unwrap(),
+            None => {
+                let re = compile_with(pattern, options)?;
+                #[cfg(test)]
+                {
+                    self.compiled += 1;
+                }
+                self.entries.truncate(REGEX_CACHE_LEN - 1);
+                (pattern.to_string(), options, re)
+            }
+        };
+        let re = entry.2.clone();
+        self.entries.push_front(entry);
+        Ok(re)
+    }
+}
+
+/// A compiled pattern, for running the same search over and over without
+/// going through the cache each time.
+#[derive(Clone, Debug)]
+pub struct Matcher {
+    re: Regex,
+}
+
+impl Matcher {
+    pub fn count_matches(&self, buf: &Buffer) -> usize {
+        buf.count_matches_re(&self.re)
+    }
+
+    pub fn find_all(&self, buf: &Buffer) -> Vec<Dot> {
+        buf.find_all_re(&self.re)
+    }
+
+    pub fn find_next(&self, buf: &Buffer, dot: &Dot, wrap: bool) -> Result<Option<Dot>, TextError> {
+        buf.find_next_re(&self.re, dot, wrap)
+    }
+
+    pub fn find_prev(&self, buf: &Buffer, dot: &Dot, wrap: bool) -> Result<Option<Dot>, TextError> {
+        buf.find_prev_re(&self.re, dot, wrap)
+    }
+}
+
+// The rope is handed to the regex engine chunk by chunk through a
+// RopeyCursor, so the buffer is never collected into a String.
+fn regex_index(text: &Rope, pattern: &str, origin: usize) -> Result<usize, TextError> {
+    regex_span(text, &compile(pattern)?, pattern, origin).map(|(from, _)| from)
+}
+
+// The first match of re, compiled from pattern, starting at or after origin.
+fn regex_span(
+    text: &Rope,
+    re: &Regex,
+    pattern: &str,
+    origin: usize,
+) -> Result<(usize, usize), TextError> {
+    let len = text.len_chars();
+    if origin > len {
+        return Err(ropey::Error::CharIndexOutOfBounds(origin, len).into());
+    }
+    matches(text, re, origin, len)
+        .next()
+        .ok_or_else(|| TextError::NoMatch(pattern.to_string()))
+}
+
+// The regex engine only searches forward, so walk every match and keep the
+// last one ending at or before the origin.
+fn regex_index_before(text: &Rope, pattern: &str, origin: usize) -> Result<usize, TextError> {
+    let origin = text.try_char_to_byte(origin)?;
+    let re = compile(pattern)?;
+    match re
+        .find_iter(Input::new(text.slice(..)))
+        .take_while(|m| m.end() <= origin)
+        .last()
+    {
+        Some(m) => Ok(text.byte_to_char(m.start())),
+        None => Err(TextError::NoMatch(pattern.to_string())),
+    }
+}
+
+// Char spans of the non-overlapping matches within from..to. The search
+// runs over the whole rope so anchors and word boundaries see the text
+// around the span.
+fn match_spans(text: &Rope, re: &Regex, from: usize, to: usize) -> Vec<(usize, usize)> {
+    matches(text, re, from, to).collect()
+}
+
+// Lazy match_spans, for searches that stop early.
+fn matches<'t>(
+    text: &'t Rope,
+    re: &'t Regex,
+    from: usize,
+    to: usize,
+) -> impl Iterator<Item = (usize, usize)> + 't {
+    let input = Input::new(text.slice(..)).range(text.char_to_byte(from)..text.char_to_byte(to));
+    re.find_iter(input)
+        .map(|m| (text.byte_to_char(m.start()), text.byte_to_char(m.end())))
+}
+
+// Like match_spans, with the replacement expanded for each match. `$1` and
+// `$name` refer to capture groups, `$$` is a literal dollar.
+fn substitutions(
+    text: &Rope,
+    re: &Regex,
+    from: usize,
+    to: usize,
+    replacement: &str,
+) -> Vec<(usize, usize, String)> {
+    let input = Input::new(text.slice(..)).range(text.char_to_byte(from)..text.char_to_byte(to));
+    re.captures_iter(input)
+        .filter_map(|caps| {
+            let m = caps.get_match()?;
+            let mut dst = String::new();
+            interpolate::string(
+                replacement,
+                |index, dst| {
+                    if let Some(group) = caps.get_group(index) {
+                        text.byte_slice(group.range())
+                            .chunks()
+                            .for_each(|chunk| dst.push_str(chunk));
+                    }
+                },
+                |name| caps.group_info().to_index(caps.pattern()?, name),
+                &mut dst,
+            );
+            Some((
+                text.byte_to_char(m.start()),
+                text.byte_to_char(m.end()),
+                dst,
+            ))
+        })
+        .collect()
+}
+
+fn relative_index(text: &Rope, n: isize, from: usize, to: usize) -> Result<usize, TextError> {
+    let len = text.len_chars();
+    let idx = match n {
+        ..0 => from
+            .checked_sub(n.unsigned_abs())
+            .ok_or(ropey::Error::CharIndexOutOfBounds(0, len))?,
+        _ => to.saturating_add(n as usize),
+    };
+    match idx.cmp(&len) {
+        Ordering::Greater => Err(ropey::Error::CharIndexOutOfBounds(idx, len).into()),
+        _ => Ok(idx),
+    }
+}
+
+// Grapheme boundaries are found by feeding the rope's chunks to a
+// GraphemeCursor, so combining sequences and emoji are never split.
+fn prev_grapheme_boundary(text: &Rope, idx: usize) -> usize {
+    let byte_idx = text.char_to_byte(idx);
+    let (mut chunk, mut chunk_start, _, _) = text.chunk_at_byte(byte_idx);
+    let mut cursor = GraphemeCursor::new(byte_idx, text.len_bytes(), true);
+    loop {
+        match cursor.prev_boundary(chunk, chunk_start) {
+            Ok(None) => return 0,
+            Ok(Some(n)) => return text.byte_to_char(n),
+            Err(GraphemeIncomplete::PrevChunk) => {
+                (chunk, chunk_start, _, _) = text.chunk_at_byte(chunk_start - 1);
+            }
+            Err(GraphemeIncomplete::PreContext(n)) => {
+                let (ctx, ctx_start, _, _) = text.chunk_at_byte(n - 1);
+                cursor.provide_context(ctx, ctx_start);
+            }
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+fn next_grapheme_boundary(text: &Rope, idx: usize) -> usize {
+    let byte_idx = text.char_to_byte(idx);
+    let (mut chunk, mut chunk_start, _, _) = text.chunk_at_byte(byte_idx);
+    let mut cursor = GraphemeCursor::new(byte_idx, text.len_bytes(), true);
+    loop {
+        match cursor.next_boundary(chunk, chunk_start) {
+            Ok(None) => return text.len_chars(),
+            Ok(Some(n)) => return text.byte_to_char(n),
+            Err(GraphemeIncomplete::NextChunk) => {
+                chunk_start += chunk.len();
+                (chunk, _, _, _) = text.chunk_at_byte(chunk_start);
+            }
+            Err(GraphemeIncomplete::PreContext(n)) => {
+                let (ctx, ctx_start, _, _) = text.chunk_at_byte(n - 1);
+                cursor.provide_context(ctx, ctx_start);
+            }
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+// Like Acme, a word is a run of alphanumeric chars or underscores, and any
+// run of whitespace or punctuation separates words.
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Char indices where the words of a line start, going by the Unicode word
+// boundaries, so "l'été" is one word. Segments without a word char, the
+// whitespace and punctuation between words, are skipped as Acme does. Word
+// boundaries always fall around line breaks, so lines can go one at a time.
+fn word_starts(text: &Rope, line: usize) -> Vec<usize> {
+    let start = text.line_to_byte(line);
+    Cow::from(text.line(line))
+        .split_word_bound_indices()
+        .filter(|(_, segment)| segment.chars().any(is_word_char))
+        .map(|(byte, _)| text.byte_to_char(start + byte))
+        .collect()
+}
+
+// Start of the next word, or the buffer end.
+fn next_word_start(text: &Rope, idx: usize) -> usize {
+    (text.char_to_line(idx)..text.len_lines())
+        .find_map(|line| {
+            word_starts(text, line)
+                .into_iter()
+                .find(|start| *start > idx)
+        })
+        .unwrap_or(text.len_chars())
+}
+
+// Start of the word before idx, or the buffer start.
+fn prev_word_start(text: &Rope, idx: usize) -> usize {
+    (0..=text.char_to_line(idx))
+        .rev()
+        .find_map(|line| {
+            word_starts(text, line)
+                .into_iter()
+                .rev()
+                .find(|start| *start < idx)
+        })
+        .unwrap_or(0)
+}
+
+// Words, whitespace and punctuation each form their own runs.
+fn char_class(c: char) -> u8 {
+    match c {
+        c if is_word_char(c) => 0,
+        c if c.is_whitespace() => 1,
+        _ => 2,
+    }
+}
+
+// The run of same-class chars around the char at idx, or the one before it
+// at the end of the buffer.
+fn run_around(text: &Rope, idx: usize) -> (usize, usize) {
+    let idx = match idx == text.len_chars() {
+        true => idx.saturating_sub(1),
+        false => idx,
+    };
+    let Some(class) = text.get_char(idx).map(char_class) else {
+        return (idx, idx);
+    };
+    let mut from = idx;
+    for c in text.chars_at(idx).reversed() {
+        if char_class(c) != class {
+            break;
+        }
+        from -= 1;
+    }
+    let mut to = idx;
+    for c in text.chars_at(idx) {
+        if char_class(c) != class {
+            break;
+        }
+        to += 1;
+    }
+    (from, to)
+}
+
+// Feeds f the reader's text a chunk at a time. A char split between two
+// reads is held back until it is whole.
+fn read_chunks(mut reader: impl io::Read, mut f: impl FnMut(&str)) -> Result<(), TextError> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut pending = 0;
+    let mut offset = 0;
+    loop {
+        let n = match reader.read(&mut buf[pending..]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let filled = pending + n;
+        let valid = match std::str::from_utf8(&buf[..filled]) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => {
+                let byte_offset = Some(offset + e.valid_up_to());
+                return Err(TextError::InvalidUtf8 { byte_offset });
+            }
+        };
+        f(std::str::from_utf8(&buf[..valid]).expect("checked above"));
+        buf.copy_within(valid..filled, 0);
+        pending = filled - valid;
+        offset += valid;
+    }
+    match pending {
+        0 => Ok(()),
+        _ => Err(TextError::InvalidUtf8 {
+            byte_offset: Some(offset),
+        }),
+    }
+}
+
+// Ties between two line starts go to the earlier one. The empty line after
+// a trailing newline doesn't count.
+fn percent_index(text: &Rope, pct: u8) -> usize {
+    let idx = (text.len_chars() * pct.min(100) as usize + 50) / 100;
+    let count = line_count(text);
+    let line = text.char_to_line(idx).min(count.saturating_sub(1));
+    let start = text.line_to_char(line);
+    match line + 1 < count && text.line_to_char(line + 1) - idx < idx - start {
+        true => text.line_to_char(line + 1),
+        false => start,
+    }
+}
+
+const DEFAULT_TAB_WIDTH: usize = 8;
+
+fn advance(column: usize, c: char, tab_width: usize) -> usize {
+    match c {
+        '\t' => (column / tab_width.max(1) + 1) * tab_width.max(1),
+        _ => column + 1,
+    }
+}
+
+// Leading spaces and tabs.
+fn indent_len(line: RopeSlice) -> usize {
+    line.chars().take_while(|c| *c == ' ' || *c == '\t').count()
+}
+
+// How much of a comment prefix the text starts with: all of it, the prefix
+// without its trailing whitespace, or nothing.
+fn comment_len(text: RopeSlice, prefix: &str) -> usize {
+    [prefix, prefix.trim_end()]
+        .into_iter()
+        .find(|p| !p.is_empty() && text.chars().take(p.chars().count()).eq(p.chars()))
+        .map_or(0, |p| p.chars().count())
+}
+
+fn visual_column(text: &Rope, idx: usize, tab_width: usize) -> usize {
+    let start = text.line_to_char(text.char_to_line(idx));
+    text.slice(start..idx)
+        .chars()
+        .fold(0, |column, c| advance(column, c, tab_width))
+}
+
+// The char covering column, or the end of the line's text when the line
+// is shorter.
+fn index_at_visual_column(text: &Rope, line: usize, column: usize, tab_width: usize) -> usize {
+    let start = text.line_to_char(line);
+    let len = content_len(text.line(line));
+    let mut visual = 0;
+    for (i, c) in text.line(line).chars().take(len).enumerate() {
+        visual = advance(visual, c, tab_width);
+        if visual > column {
+            return start + i;
+        }
+    }
+    start + len
+}
+
+fn line_len(text: &Rope, line: usize, newline: bool) -> Result<usize, TextError> {
+    let slice = text
+        .get_line(line)
+        .ok_or(ropey::Error::LineIndexOutOfBounds(line, text.len_lines()))?;
+    match newline {
+        true => Ok(slice.len_chars()),
+        false => Ok(content_len(slice)),
+    }
+}
+
+// Length of a line without its line break, which is either "\n" or "\r\n".
+fn content_len(line: RopeSlice) -> usize {
+    let mut len = line.len_chars();
+    if len > 0 && line.char(len - 1) == '\n' {
+        len -= 1;
+    }
+    if len > 0 && line.char(len - 1) == '\r' {
+        len -= 1;
+    }
+    len
+}
+
+// Number of lines with any text or line break in them. Unlike
+// Rope::len_lines, a trailing newline ends the last line rather than
+// starting one.
+fn line_count(text: &Rope) -> usize {
+    match text.line(text.len_lines() - 1).len_chars() {
+        0 => text.len_lines() - 1,
+        _ => text.len_lines(),
+    }
+}
+
+// The first and last lines that from..to touches. An end at the start of a
+// line doesn't touch it, unless the span is empty.
+fn line_range(text: &Rope, from: usize, to: usize) -> (usize, usize) {
+    let first = text.char_to_line(from);
+    match to > from {
+        true => (first, text.char_to_line(to - 1)),
+        false => (first, first),
+    }
+}
+
+// The middle snake of Myers' linear space diff: a run of common items
+// (x, y)..(u, v) that some shortest edit script between a and b goes
+// through, with the length d of that script.
+fn middle_snake(a: &[u32], b: &[u32]) -> (usize, usize, usize, usize, usize) {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let delta = n - m;
+    let max = (n + m + 1) / 2;
+    let offset = max + 1;
+    let mut fwd = vec![0; 2 * offset as usize + 1];
+    let mut bwd = fwd.clone();
+    let at = |k: isize| (k + offset) as usize;
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let mut x = match k == -d || (k != d && fwd[at(k - 1)] < fwd[at(k + 1)]) {
+                true => fwd[at(k + 1)],
+                false => fwd[at(k - 1)] + 1,
+            };
+            let (x0, y0) = (x, x - k);
+            while x < n && x - k < m && a[x as usize] == b[(x - k) as usize] {
+                x += 1;
+            }
+            fwd[at(k)] = x;
+            let back = delta - k;
+            if delta % 2 != 0 && back.abs() < d && x + bwd[at(back)] >= n {
+                let (u, v) = (x as usize, (x - k) as usize);
+                return (x0 as usize, y0 as usize, u, v, 2 * d as usize - 1);
+            }
+        }
+        for k in (-d..=d).step_by(2) {
+            let mut x = match k == -d || (k != d && bwd[at(k - 1)] < bwd[at(k + 1)]) {
+                true => bwd[at(k + 1)],
+                false => bwd[at(k - 1)] + 1,
+            };
+            let (x0, y0) = (x, x - k);
+            while x < n && x - k < m && a[(n - 1 - x) as usize] == b[(m - 1 - x + k) as usize] {
+                x += 1;
+            }
+            bwd[at(k)] = x;
+            let fore = delta - k;
+            if delta % 2 == 0 && fore.abs() <= d && x + fwd[at(fore)] >= n {
+                let (u, v) = ((n - x0) as usize, (m - y0) as usize);
+                return ((n - x) as usize, (m - x + k) as usize, u, v, 2 * d as usize);
+            }
+        }
+    }
+    unreachable!("a shortest edit script is at most n + m long")
+}
+
+// Pairs up the indices of a longest common subsequence of a and b, offset
+// by base, in O((n + m) d) time and O(n + m) space.
+fn common_items(a: &[u32], b: &[u32], base: (usize, usize), out: &mut Vec<(usize, usize)>) {
+    if a.is_empty() || b.is_empty() {
+        return;
+    }
+    let (x, y, u, v, d) = middle_snake(a, b);
+    if d > 1 {
+        common_items(&a[..x], &b[..y], base, out);
+        out.extend((x..u).map(|i| (base.0 + i, base.1 + y + i - x)));
+        common_items(&a[u..], &b[v..], (base.0 + u, base.1 + v), out);
+        return;
+    }
+    // One edit at most: the shorter side is a subsequence of the longer.
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            out.push((base.0 + i, base.1 + j));
+            j += 1;
+        } else if a.len() < b.len() {
+            j += 1;
+            continue;
+        }
+        i += 1;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    // Going by the first line break, as files rarely mix them.
+    fn detect(text: &Rope) -> LineEnding {
+        match text.lines().next() {
+            Some(line) if content_len(line) + 2 == line.len_chars() => LineEnding::Crlf,
+            _ => LineEnding::Lf,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+    /// Sniffs a byte order mark, falling back to UTF-8.
+    Detect,
+}
+
+impl Encoding {
+    // Also returns whether a byte order mark was found and stripped, so a
+    // UTF-8 one can be written back on save.
+    fn decode(self, input: &[u8]) -> Result<(String, Encoding, bool), TextError> {
+        let (encoding, bytes) = match self {
+            Encoding::Detect => match encoding_rs::Encoding::for_bom(input) {
+                Some((e, len)) if e == encoding_rs::UTF_16LE => (Encoding::Utf16Le, &input[len..]),
+                Some((e, len)) if e == encoding_rs::UTF_16BE => (Encoding::Utf16Be, &input[len..]),
+                Some((_, len)) => (Encoding::Utf8, &input[len..]),
+                None => (Encoding::Utf8, input),
+            },
+            encoding => (encoding, input),
+        };
+        if encoding == Encoding::Utf8
+            && let Err(e) = std::str::from_utf8(bytes)
+        {
+            let bom = input.len() - bytes.len();
+            let byte_offset = Some(bom + e.valid_up_to());
+            return Err(TextError::InvalidUtf8 { byte_offset });
+        }
+        let (text, had_errors) = encoding.codec().decode_without_bom_handling(bytes);
+        if had_errors {
+            let msg = format!("invalid {encoding:?} text");
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg).into());
+        }
+        let bom = bytes.len() < input.len();
+        Ok((text.into_owned(), encoding, bom))
+    }
+
+    // encoding_rs can't encode to UTF-16, so it is done by hand, with a byte
+    // order mark so the file can be detected again.
+    fn encode(self, text: &str) -> io::Result<Vec<u8>> {
+        let units = text.encode_utf16();
+        match self {
+            Encoding::Utf16Le => Ok([0xfeff]
+                .into_iter()
+                .chain(units)
+                .flat_map(u16::to_le_bytes)
+                .collect()),
+            Encoding::Utf16Be => Ok([0xfeff]
+                .into_iter()
+                .chain(units)
+                .flat_map(u16::to_be_bytes)
+                .collect()),
+            encoding => match self.codec().encode(text) {
+                (bytes, _, false) => Ok(bytes.into_owned()),
+                (_, _, true) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("text can't be encoded as {encoding:?}"),
+                )),
+            },
+        }
+    }
+
+    // Latin-1 is decoded as windows-1252, its superset, as browsers do.
+    fn codec(self) -> &'static encoding_rs::Encoding {
+        match self {
+            Encoding::Utf8 | Encoding::Detect => encoding_rs::UTF_8,
+            Encoding::Utf16Le => encoding_rs::UTF_16LE,
+            Encoding::Utf16Be => encoding_rs::UTF_16BE,
+            Encoding::Latin1 => encoding_rs::WINDOWS_1252,
+        }
+    }
+}
+
+// Writes the text with every line break replaced by ending.
+fn write_lines(text: &Rope, ending: LineEnding, mut writer: impl Write) -> io::Result<()> {
+    for line in text.lines() {
+        let len = content_len(line);
+        for chunk in line.slice(..len).chunks() {
+            writer.write_all(chunk.as_bytes())?;
+        }
+        if len < line.len_chars() {
+            writer.write_all(ending.as_str().as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn span(text: &Rope, from: usize, to: usize) -> Result<RopeSlice<'_>, TextError> {
+    if from > to {
+        return Err(ropey::Error::CharRangeInvalid(from, to).into());
+    }
+    if to > text.len_chars() {
+        let len = text.len_chars();
+        return Err(ropey::Error::CharRangeOutOfBounds(Some(from), Some(to), len).into());
+    }
+    Ok(text.slice(from..to))
 }
 
 impl<'a> Addr {
-    pub fn as_index(&self, text: &'a Arc<Mutex<Rope>>) -> ropey::Result<usize> {
+    pub fn as_index(&self, text: &'a Arc<RwLock<Rope>>) -> Result<usize, TextError> {
+        self.as_index_in(&read(text))
+    }
+
+    pub fn as_index_in(&self, text: &Rope) -> Result<usize, TextError> {
         match self {
             Addr::Index(idx) => Ok(*idx),
             Addr::Coordinates(line, column) => {
-                let text = text.lock().unwrap();
-                let idx = text.try_line_to_char(*line)?;
-                Ok(idx + column)
+                let start = text.try_line_to_char(*line)?;
+                let len = text.get_line(*line).map_or(0, |l| l.len_chars());
+                match column.cmp(&len) {
+                    Ordering::Greater => {
+                        Err(ropey::Error::CharIndexOutOfBounds(*column, len).into())
+                    }
+                    _ => Ok(start + column),
+                }
+            }
+            Addr::LineStart(line) => Ok(text.try_line_to_char(*line)?),
+            Addr::LineStartNonBlank(line) => {
+                let start = text.try_line_to_char(*line)?;
+                let slice = text
+                    .get_line(*line)
+                    .ok_or(ropey::Error::LineIndexOutOfBounds(*line, text.len_lines()))?;
+                match indent_len(slice) {
+                    indent if indent < content_len(slice) => Ok(start + indent),
+                    _ => Ok(start),
+                }
             }
-            Addr::LineStart(line) => text.lock().unwrap().try_line_to_char(*line),
-            Addr::LineEnd(line) => {
-                let text = text.lock().unwrap();
-                Ok(text.try_line_to_char(*line)? + text.line(*line).len_chars())
+            Addr::LineEnd(line) => Ok(text.try_line_to_char(*line)? + line_len(text, *line, true)?),
+            Addr::LineEndBeforeNewline(line) => {
+                Ok(text.try_line_to_char(*line)? + line_len(text, *line, false)?)
             }
             Addr::BufferStart => Ok(0),
-            Addr::BufferEnd => Ok(text.lock().unwrap().len_chars() - 1),
+            Addr::BufferEnd => Ok(text.len_chars()),
+            Addr::Regex(pattern) => regex_index(text, pattern, 0),
+            Addr::RegexBackward(pattern) => regex_index_before(text, pattern, text.len_chars()),
+            Addr::Relative(n) => relative_index(text, *n, 0, 0),
+            Addr::Mark(mark) => Ok(mark.get()),
+            Addr::Byte(byte) => {
+                let idx = text.try_byte_to_char(*byte)?;
+                match text.char_to_byte(idx) == *byte {
+                    true => Ok(idx),
+                    false => {
+                        Err(ropey::Error::ByteRangeNotCharBoundary(Some(*byte), Some(*byte)).into())
+                    }
+                }
+            }
+            Addr::Percent(pct) => Ok(percent_index(text, *pct)),
+            Addr::Utf16(line, column) => {
+                let start = text.try_line_to_char(*line)?;
+                let len = text.get_line(*line).map_or(0, |l| l.len_utf16_cu());
+                if *column > len {
+                    return Err(ropey::Error::Utf16IndexOutOfBounds(*column, len).into());
+                }
+                let target = text.char_to_utf16_cu(start) + column;
+                let idx = text.utf16_cu_to_char(target);
+                match text.char_to_utf16_cu(idx) == target {
+                    true => Ok(idx),
+                    false => Err(TextError::InvalidAddr(format!(
+                        "utf-16 column {column} of line {line} splits a surrogate pair"
+                    ))),
+                }
+            }
+        }
+    }
+
+    fn as_index_around(&self, text: &Rope, from: &Addr, to: &Addr) -> Result<usize, TextError> {
+        match self {
+            Addr::Relative(n) => {
+                relative_index(text, *n, from.as_index_in(text)?, to.as_index_in(text)?)
+            }
+            Addr::RegexBackward(pattern) => {
+                regex_index_before(text, pattern, from.as_index_in(text)?)
+            }
+            _ => self.as_index_in(text),
         }
     }
 
-    pub fn as_coordinates(&self, text: &'a Arc<Mutex<Rope>>) -> ropey::Result<(usize, usize)> {
+    pub fn cmp_in(&self, other: &Addr, text: &Rope) -> Result<Ordering, TextError> {
+        let (a, b) = (self.as_index_in(text)?, other.as_index_in(text)?);
+        span(text, a.min(b), a.max(b))?;
+        Ok(a.cmp(&b))
+    }
+
+    pub fn as_coordinates(&self, text: &'a Arc<RwLock<Rope>>) -> Result<(usize, usize), TextError> {
+        self.as_coordinates_in(&read(text))
+    }
+
+    pub fn as_coordinates_in(&self, text: &Rope) -> Result<(usize, usize), TextError> {
         match self {
             Addr::Index(idx) => {
-                let text = text.lock().unwrap();
                 let line = text.try_char_to_line(*idx)?;
                 let column = idx - text.try_line_to_char(line)?;
                 Ok((line, column))
             }
             Addr::Coordinates(line, column) => Ok((*line, *column)),
             Addr::LineStart(line) => Ok((*line, 0)),
-            Addr::LineEnd(line) => Ok((*line, text.lock().unwrap().line(*line).len_chars() - 1)),
+            Addr::LineEnd(line) => Ok((*line, line_len(text, *line, true)?)),
+            Addr::LineEndBeforeNewline(line) => Ok((*line, line_len(text, *line, false)?)),
             Addr::BufferStart => Ok((0, 0)),
             Addr::BufferEnd => {
-                let text = text.lock().unwrap();
-                let line = text.try_char_to_line(text.len_chars() - 1)?;
-                let column = text.len_chars() - 1 - text.try_line_to_char(line)?;
+                let line = text.try_char_to_line(text.len_chars())?;
+                let column = text.len_chars() - text.try_line_to_char(line)?;
+                Ok((line, column))
+            }
+            Addr::Regex(_)
+            | Addr::RegexBackward(_)
+            | Addr::Relative(_)
+            | Addr::Mark(_)
+            | Addr::Byte(_)
+            | Addr::Utf16(..)
+            | Addr::Percent(_)
+            | Addr::LineStartNonBlank(_) => {
+                let idx = self.as_index_in(text)?;
+                let line = text.try_char_to_line(idx)?;
+                let column = idx - text.try_line_to_char(line)?;
                 Ok((line, column))
             }
         }
     }
 
-    pub fn move_left(&mut self, text: &'a Arc<Mutex<Rope>>, n: usize) -> ropey::Result<()> {
-        match self.as_index(text)?.cmp(&n) {
-            Ordering::Less => Err(ropey::Error::CharIndexOutOfBounds(
-                0,
-                text.lock().unwrap().len_chars(),
-            )),
-            _ => {
-                *self = Addr::Index(self.as_index(text)? - n);
-                Ok(())
-            }
+    pub fn as_utf16(&self, text: &'a Arc<RwLock<Rope>>) -> Result<(usize, usize), TextError> {
+        self.as_utf16_in(&read(text))
+    }
+
+    pub fn as_utf16_in(&self, text: &Rope) -> Result<(usize, usize), TextError> {
+        let idx = self.as_index_in(text)?;
+        let line = text.try_char_to_line(idx)?;
+        let start = text.line_to_char(line);
+        Ok((
+            line,
+            text.char_to_utf16_cu(idx) - text.char_to_utf16_cu(start),
+        ))
+    }
+
+    pub fn move_left(&mut self, text: &'a Arc<RwLock<Rope>>, n: usize) -> Result<(), TextError> {
+        self.move_left_in(&read(text), n)
+    }
+
+    pub fn move_right(&mut self, text: &'a Arc<RwLock<Rope>>, n: usize) -> Result<(), TextError> {
+        self.move_right_in(&read(text), n)
+    }
+
+    fn move_left_in(&mut self, text: &Rope, n: usize) -> Result<(), TextError> {
+        let idx = self.left_in(text, n)?;
+        self.place(idx);
+        Ok(())
+    }
+
+    fn move_right_in(&mut self, text: &Rope, n: usize) -> Result<(), TextError> {
+        let idx = self.right_in(text, n)?;
+        self.place(idx);
+        Ok(())
+    }
+
+    // Where a move by n chars would land, without moving.
+    fn left_in(&self, text: &Rope, n: usize) -> Result<usize, TextError> {
+        let idx = self.as_index_in(text)?;
+        let len = text.len_chars();
+        match idx.checked_sub(n) {
+            Some(idx) if idx <= len => Ok(idx),
+            Some(idx) => Err(ropey::Error::CharIndexOutOfBounds(idx, len).into()),
+            None => Err(ropey::Error::CharIndexOutOfBounds(0, len).into()),
         }
     }
 
-    //
-    //
-    //
-    //
-    // NEED TO CALL MUTEX LOCK HIGHER IN THE CALL HIERARCHY (probably at dot level)
-    //
-    //
-    //
-    //
+    fn right_in(&self, text: &Rope, n: usize) -> Result<usize, TextError> {
+        let idx = self.as_index_in(text)?;
+        let len = text.len_chars();
+        match idx.checked_add(n) {
+            Some(idx) if idx <= len => Ok(idx),
+            _ => Err(ropey::Error::CharIndexOutOfBounds(idx.saturating_add(n), len).into()),
+        }
+    }
 
-    pub fn move_right(&mut self, text: &'a Arc<Mutex<Rope>>, n: usize) -> ropey::Result<()> {
-        let text = text.lock().unwrap();
-        match (self.as_index(text)? + n).cmp(&text.len_chars()) {
-            Ordering::Greater => Err(ropey::Error::CharIndexOutOfBounds(
-                text.len_chars(),
-                text.len_chars(),
-            )),
-            _ => {
-                *self = Addr::Index(self.as_index(text)? + n);
-                Ok(())
+    fn left_graphemes_in(&self, text: &Rope, n: usize) -> Result<usize, TextError> {
+        let mut idx = self.as_index_in(text)?;
+        span(text, idx, idx)?;
+        for _ in 0..n {
+            match idx {
+                0 => return Err(ropey::Error::CharIndexOutOfBounds(0, text.len_chars()).into()),
+                _ => idx = prev_grapheme_boundary(text, idx),
+            }
+        }
+        Ok(idx)
+    }
+
+    fn right_graphemes_in(&self, text: &Rope, n: usize) -> Result<usize, TextError> {
+        let mut idx = self.as_index_in(text)?;
+        let len = text.len_chars();
+        span(text, idx, idx)?;
+        for _ in 0..n {
+            match idx.cmp(&len) {
+                Ordering::Less => idx = next_grapheme_boundary(text, idx),
+                _ => return Err(ropey::Error::CharIndexOutOfBounds(len + 1, len).into()),
             }
         }
+        Ok(idx)
+    }
+
+    // Marks are moved rather than replaced, so sticky dots stay sticky.
+    fn place(&mut self, idx: usize) {
+        match self {
+            Addr::Mark(mark) => mark.set(idx),
+            _ => *self = Addr::Index(idx),
+        }
     }
 }
 
-#[derive(Clone)]
-pub struct Dot<'a> {
-    text: &'a Arc<Mutex<Rope>>,
+pub struct DotText<'a> {
+    text: RwLockReadGuard<'a, Rope>,
+    from: usize,
+    to: usize,
+}
+
+impl DotText<'_> {
+    pub fn slice(&self) -> RopeSlice<'_> {
+        self.text.slice(self.from..self.to)
+    }
+
+    /// The chars from the end of the dot back to its start, walking the rope
+    /// backward rather than collecting it. This lives on the guard since a
+    /// Dot can't lend out its locked rope.
+    pub fn chars_rev(&self) -> impl Iterator<Item = char> + '_ {
+        self.text
+            .chars_at(self.to)
+            .reversed()
+            .take(self.to - self.from)
+    }
+}
+
+/// Yanked text, kept apart from any system clipboard.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Register {
+    text: Rope,
+}
+
+impl Register {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(&self) -> RopeSlice<'_> {
+        self.text.slice(..)
+    }
+}
+
+/// Like DotText, this holds the buffer locked until dropped.
+pub struct Lines<'a> {
+    text: RwLockReadGuard<'a, Rope>,
+}
+
+impl Lines<'_> {
+    pub fn iter(&self) -> ropey::iter::Lines<'_> {
+        self.text.lines()
+    }
+}
+
+impl<'a> IntoIterator for &'a Lines<'_> {
+    type Item = RopeSlice<'a>;
+    type IntoIter = ropey::iter::Lines<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Char indices paired with their chars. Like Lines, this holds the buffer
+/// locked until dropped. A ropey Chars can't live next to the guard it would
+/// borrow from, so the chars come a rope chunk at a time, with one O(log n)
+/// lookup per chunk rather than per char.
+pub struct CharIndices<'a> {
+    text: RwLockReadGuard<'a, Rope>,
+    idx: usize,
+    end: usize,
+    chunk: std::vec::IntoIter<char>,
+}
+
+impl<'a> CharIndices<'a> {
+    fn new(text: RwLockReadGuard<'a, Rope>, idx: usize, end: usize) -> Self {
+        CharIndices {
+            text,
+            idx,
+            end,
+            chunk: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Iterator for CharIndices<'_> {
+    type Item = (usize, char);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx >= self.end {
+            return None;
+        }
+        if self.chunk.len() == 0 {
+            let (chunk, _, start, _) = self.text.chunk_at_char(self.idx);
+            self.chunk = chunk
+                .chars()
+                .skip(self.idx - start)
+                .take(self.end - self.idx)
+                .collect::<Vec<_>>()
+                .into_iter();
+        }
+        let c = self.chunk.next()?;
+        self.idx += 1;
+        Some((self.idx - 1, c))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.end.saturating_sub(self.idx);
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for CharIndices<'_> {}
+
+pub struct Dot {
+    text: Arc<RwLock<Rope>>,
+    marks: Arc<Mutex<Marks>>,
+    regexes: Arc<Mutex<RegexCache>>,
     from: Addr,
     to: Addr,
+    tab_width: usize,
+    // The column vertical moves aim for, and where the last one left the
+    // dot. It only holds while the dot is still there.
+    goal: Option<(usize, usize)>,
+}
+
+// The rope can't be locked from fmt, so this shows the unresolved addresses.
+// See debug_resolved for the indices.
+impl fmt::Debug for Dot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dot")
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .finish()
+    }
 }
 
-impl<'a> Dot<'a> {
-    pub fn new(buf: &'a Buffer) -> Dot<'a> {
+// A copy of a sticky dot gets marks of its own, so moving one doesn't move
+// the other.
+impl Clone for Dot {
+    fn clone(&self) -> Self {
+        let mut marks = lock(&self.marks);
+        let mut copy = |addr: &Addr| match addr {
+            Addr::Mark(mark) => Addr::Mark(marks.add(mark.get())),
+            addr => addr.clone(),
+        };
+        let (from, to) = (copy(&self.from), copy(&self.to));
+        drop(marks);
         Dot {
-            text: &buf.text,
-            from: Addr::BufferStart,
-            to: Addr::BufferEnd,
+            text: Arc::clone(&self.text),
+            marks: Arc::clone(&self.marks),
+            regexes: Arc::clone(&self.regexes),
+            from,
+            to,
+            tab_width: self.tab_width,
+            goal: self.goal,
         }
     }
-    pub fn left_right(&mut self, left: Addr, right: Addr) -> ropey::Result<()> {
-        self.from = Addr::Index(left.as_index(self.text)?);
-        self.to = Addr::Index(right.as_index(self.text)?);
-        Ok(())
+}
+
+impl Dot {
+    pub fn new(buf: &Buffer) -> Dot {
+        Dot {
+            text: Arc::clone(&buf.text),
+            marks: Arc::clone(&buf.marks),
+            regexes: Arc::clone(&buf.regexes),
+            from: Addr::BufferStart,
+            to: Addr::BufferEnd,
+            tab_width: buf.tab_width,
+            goal: None,
+        }
+    }
+
+    fn from_indices(buf: &Buffer, from: usize, to: usize) -> Dot {
+        Dot {
+            text: Arc::clone(&buf.text),
+            marks: Arc::clone(&buf.marks),
+            regexes: Arc::clone(&buf.regexes),
+            from: Addr::Index(from),
+            to: Addr::Index(to),
+            tab_width: buf.tab_width,
+            goal: None,
+        }
+    }
+
+    /// The indices are the buffer's, not counted from the start of the dot.
+    pub fn char_indices(&self) -> Result<CharIndices<'_>, TextError> {
+        let text = read(&self.text);
+        let (idx, end) = self.indices_in(&text)?;
+        Ok(CharIndices::new(text, idx, end))
+    }
+
+    /// Returns the selected text without copying it out of the rope. The
+    /// buffer stays locked until the returned `DotText` is dropped, so keep
+    /// it short-lived and don't edit the buffer while holding it.
+    pub fn text(&self) -> Result<DotText<'_>, TextError> {
+        let text = read(&self.text);
+        let from = self.from.as_index_in(&text)?;
+        let to = self.to.as_index_in(&text)?;
+        span(&text, from, to)?;
+        Ok(DotText { text, from, to })
+    }
+
+    pub fn debug_resolved(&self) -> String {
+        match self.indices() {
+            Ok((from, to)) => format!("Dot({from}..{to})"),
+            Err(e) => format!("Dot({:?}..{:?}: {e})", self.from, self.to),
+        }
+    }
+
+    pub fn len(&self) -> Result<usize, TextError> {
+        let (from, to) = self.indices()?;
+        Ok(to - from)
+    }
+
+    pub fn is_empty(&self) -> Result<bool, TextError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Dots are half-open, so an empty dot contains nothing, not even its own
+    /// position, and never overlaps anything.
+    pub fn contains(&self, addr: Addr) -> Result<bool, TextError> {
+        let text = read(&self.text);
+        let (from, to) = self.indices_in(&text)?;
+        let idx = addr.as_index_in(&text)?;
+        Ok(from <= idx && idx < to)
+    }
+
+    pub fn overlaps(&self, other: &Dot) -> Result<bool, TextError> {
+        if !Arc::ptr_eq(&self.text, &other.text) {
+            return Err(TextError::InvalidAddr(
+                "dots are on different buffers".to_string(),
+            ));
+        }
+        let text = read(&self.text);
+        let (from, to) = self.indices_in(&text)?;
+        let (other_from, other_to) = other.indices_in(&text)?;
+        Ok(from < to && other_from < other_to && from < other_to && other_from < to)
+    }
+
+    pub fn left_right(&mut self, left: Addr, right: Addr) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let from = left.as_index_around(&text, &self.from, &self.to)?;
+        let to = right.as_index_around(&text, &self.from, &self.to)?;
+        self.from.place(from);
+        self.to.place(to);
+        Ok(())
+    }
+
+    /// Collapses the dot n chars after its end, or -n chars before its start.
+    pub fn move_to_relative(&mut self, n: isize) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let idx = Addr::Relative(n).as_index_around(&text, &self.from, &self.to)?;
+        self.from.place(idx);
+        self.to.place(idx);
+        Ok(())
+    }
+
+    /// Spans `to` chars rightward from anchor. A span past either end of the
+    /// buffer is an error and leaves the dot as it was.
+    pub fn anchor_left(&mut self, anchor: Addr, to: Addr) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let len = text.len_chars();
+        let anchor = anchor.as_index_in(&text)?;
+        let n = to.as_index_in(&text)?;
+        let end = match anchor.checked_add(n) {
+            Some(end) if end <= len => end,
+            _ => {
+                return Err(
+                    ropey::Error::CharIndexOutOfBounds(anchor.saturating_add(n), len).into(),
+                );
+            }
+        };
+        self.from.place(anchor);
+        self.to.place(end);
+        Ok(())
+    }
+
+    /// Spans `from` chars leftward from anchor.
+    pub fn anchor_right(&mut self, from: Addr, anchor: Addr) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let len = text.len_chars();
+        let anchor = anchor.as_index_in(&text)?;
+        let n = from.as_index_in(&text)?;
+        span(&text, anchor, anchor)?;
+        let start = anchor
+            .checked_sub(n)
+            .ok_or(ropey::Error::CharIndexOutOfBounds(0, len))?;
+        self.from.place(start);
+        self.to.place(anchor);
+        Ok(())
+    }
+
+    /// Both ends are resolved before either moves, so a move that fails
+    /// leaves the dot where it was.
+    pub fn move_left(&mut self, n: usize) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let from = self.from.left_in(&text, n)?;
+        let to = self.to.left_in(&text, n)?;
+        self.from.place(from);
+        self.to.place(to);
+        Ok(())
+    }
+
+    pub fn move_right(&mut self, n: usize) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let from = self.from.right_in(&text, n)?;
+        let to = self.to.right_in(&text, n)?;
+        self.from.place(from);
+        self.to.place(to);
+        Ok(())
+    }
+
+    /// Clamped moves shift the whole dot by at most n chars, stopping at the
+    /// buffer edges, and return how far it actually moved.
+    pub fn move_left_clamped(&mut self, n: usize) -> Result<usize, TextError> {
+        let text = read(&self.text);
+        let (from, _) = self.indices_in(&text)?;
+        let n = n.min(from);
+        self.from.move_left_in(&text, n)?;
+        self.to.move_left_in(&text, n)?;
+        Ok(n)
+    }
+
+    pub fn move_right_clamped(&mut self, n: usize) -> Result<usize, TextError> {
+        let text = read(&self.text);
+        let (_, to) = self.indices_in(&text)?;
+        let n = n.min(text.len_chars() - to);
+        self.from.move_right_in(&text, n)?;
+        self.to.move_right_in(&text, n)?;
+        Ok(n)
+    }
+
+    pub fn move_left_grapheme(&mut self, n: usize) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let from = self.from.left_graphemes_in(&text, n)?;
+        let to = self.to.left_graphemes_in(&text, n)?;
+        self.from.place(from);
+        self.to.place(to);
+        Ok(())
+    }
+
+    pub fn move_right_grapheme(&mut self, n: usize) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let from = self.from.right_graphemes_in(&text, n)?;
+        let to = self.to.right_graphemes_in(&text, n)?;
+        self.from.place(from);
+        self.to.place(to);
+        Ok(())
+    }
+
+    /// Word motions collapse the dot onto the start of the n-th next word after
+    /// its end, or previous word before its start. They stop at the buffer
+    /// edges instead of failing.
+    pub fn move_word_right(&mut self, n: usize) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let (_, mut idx) = self.indices_in(&text)?;
+        for _ in 0..n {
+            idx = next_word_start(&text, idx);
+        }
+        self.from.place(idx);
+        self.to.place(idx);
+        Ok(())
+    }
+
+    pub fn move_word_left(&mut self, n: usize) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let (mut idx, _) = self.indices_in(&text)?;
+        for _ in 0..n {
+            idx = prev_word_start(&text, idx);
+        }
+        self.from.place(idx);
+        self.to.place(idx);
+        Ok(())
+    }
+
+    /// Selects the next match of pattern starting at or after the end of dot,
+    /// the way sam's /re/ searches forward from dot. With wrap, a search that
+    /// runs off the end starts over from the start of the buffer.
+    pub fn move_to_next_match(&mut self, pattern: &str, wrap: bool) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let (_, origin) = self.indices_in(&text)?;
+        let re = self.regex(pattern)?;
+        let (from, to) = match regex_span(&text, &re, pattern, origin) {
+            Err(TextError::NoMatch(_)) if wrap => regex_span(&text, &re, pattern, 0)?,
+            found => found?,
+        };
+        self.from.place(from);
+        self.to.place(to);
+        Ok(())
+    }
+
+    /// Vertical motions collapse the dot onto the goal column of the line
+    /// above its start, or below its end, or that line's end when it is
+    /// shorter. The goal is the visual column the dot was at before the
+    /// first of a run of vertical moves, and is kept across them, so going
+    /// through a short line and back lands where it began. Moving the dot
+    /// any other way sets a new goal. They stop at the first and last lines.
+    pub fn move_up(&mut self) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let (from, to) = self.indices_in(&text)?;
+        let goal = self.goal_column(&text, from, to);
+        let line = text.char_to_line(from);
+        let idx = match line {
+            0 => from,
+            _ => index_at_visual_column(&text, line - 1, goal, self.tab_width),
+        };
+        self.from.place(idx);
+        self.to.place(idx);
+        self.goal = Some((goal, idx));
+        Ok(())
+    }
+
+    pub fn move_down(&mut self) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let (from, to) = self.indices_in(&text)?;
+        let goal = self.goal_column(&text, to, from);
+        let line = text.char_to_line(to);
+        let idx = match line + 1 < line_count(&text) {
+            true => index_at_visual_column(&text, line + 1, goal, self.tab_width),
+            false => to,
+        };
+        self.from.place(idx);
+        self.to.place(idx);
+        self.goal = Some((goal, idx));
+        Ok(())
+    }
+
+    // The kept goal while the dot is still where the last vertical move left
+    // it, or else the column of the end the move leaves from.
+    fn goal_column(&self, text: &Rope, idx: usize, other: usize) -> usize {
+        match self.goal {
+            Some((goal, at)) if idx == at && other == at => goal,
+            _ => visual_column(text, idx, self.tab_width),
+        }
+    }
+
+    /// Grows the dot out to the nearest pair of open and close matches around
+    /// it, delimiters included, like sam's { and }. Pairs nest, unless open
+    /// and close are the same pattern, as for quotes. Returns false, leaving
+    /// the dot alone, when nothing encloses it.
+    pub fn expand_to(&mut self, open: &str, close: &str) -> Result<bool, TextError> {
+        let (open_re, close_re) = (self.regex(open)?, self.regex(close)?);
+        let text = read(&self.text);
+        let (from, to) = self.indices_in(&text)?;
+        let len = text.len_chars();
+        let enclosing = match open == close {
+            true => {
+                let before: Vec<_> = matches(&text, &open_re, 0, from).collect();
+                match before.len() % 2 {
+                    1 => before
+                        .last()
+                        .copied()
+                        .zip(matches(&text, &open_re, to, len).next()),
+                    _ => None,
+                }
+            }
+            false => {
+                let tokens = |from, to| {
+                    let mut tokens: Vec<_> = matches(&text, &open_re, from, to)
+                        .map(|m| (m, true))
+                        .chain(matches(&text, &close_re, from, to).map(|m| (m, false)))
+                        .collect();
+                    tokens.sort_by_key(|((start, _), _)| *start);
+                    tokens
+                };
+                let mut opens = Vec::new();
+                for (m, is_open) in tokens(0, from) {
+                    match is_open {
+                        true => opens.push(m),
+                        false => {
+                            opens.pop();
+                        }
+                    }
+                }
+                let mut depth = 0usize;
+                let close = tokens(to, len).into_iter().find_map(|(m, is_open)| {
+                    match (is_open, depth) {
+                        (true, _) => depth += 1,
+                        (false, 0) => return Some(m),
+                        (false, _) => depth -= 1,
+                    }
+                    None
+                });
+                opens.last().copied().zip(close)
+            }
+        };
+        let Some(((start, _), (_, end))) = enclosing else {
+            return Ok(false);
+        };
+        self.from.place(start);
+        self.to.place(end);
+        Ok(true)
+    }
+
+    /// Selects the word under at, like a double click. On whitespace or
+    /// punctuation it selects that run instead.
+    pub fn select_word_at(&mut self, at: Addr) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let idx = at.as_index_around(&text, &self.from, &self.to)?;
+        span(&text, idx, idx)?;
+        let (from, to) = run_around(&text, idx);
+        self.from.place(from);
+        self.to.place(to);
+        Ok(())
+    }
+
+    pub fn yank(&self, buf: &Buffer, reg: &mut Register) -> Result<(), TextError> {
+        buf.check_dot(self)?;
+        reg.text = Rope::from(buf.try_get_strict(self)?);
+        Ok(())
+    }
+
+    /// Selects the line with its newline, if it has one.
+    pub fn select_line(&mut self, line: usize) -> Result<(), TextError> {
+        self.select_lines(line, line + 1)
+    }
+
+    /// Selects lines start..end, through the newline of the last one.
+    pub fn select_lines(&mut self, start: usize, end: usize) -> Result<(), TextError> {
+        let text = read(&self.text);
+        if start >= end {
+            return Err(TextError::InvalidAddr(format!(
+                "empty line range {start}..{end}"
+            )));
+        }
+        let from = text.try_line_to_char(start)?;
+        let to = Addr::LineEnd(end - 1).as_index_in(&text)?;
+        self.from.place(from);
+        self.to.place(to);
+        Ok(())
+    }
+
+    /// Grows the dot to whole lines: from goes to the start of its line and
+    /// to past the line break of the line it ends in. A dot ending at the
+    /// start of a line doesn't take that line, unless it is empty.
+    pub fn round_to_lines(&mut self) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let (from, to) = self.indices_in(&text)?;
+        let (first, last) = line_range(&text, from, to);
+        self.from.place(text.line_to_char(first));
+        self.to.place(text.line_to_char(last + 1));
+        Ok(())
+    }
+
+    /// Shrinks the dot past whitespace at both ends, newlines included. A dot
+    /// of nothing but whitespace collapses onto its start.
+    pub fn trim_whitespace(&mut self) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let (from, to) = self.indices_in(&text)?;
+        let leading = text
+            .slice(from..to)
+            .chars()
+            .take_while(|c| c.is_whitespace())
+            .count();
+        if leading == to - from {
+            self.from.place(from);
+            self.to.place(from);
+            return Ok(());
+        }
+        let trailing = text
+            .chars_at(to)
+            .reversed()
+            .take_while(|c| c.is_whitespace())
+            .count();
+        self.from.place(from + leading);
+        self.to.place(to - trailing);
+        Ok(())
+    }
+
+    /// Grows the dot leftward by moving `from`.
+    pub fn extend_left(&mut self, n: usize) -> Result<(), TextError> {
+        let text = read(&self.text);
+        self.from.move_left_in(&text, n)?;
+        Ok(())
+    }
+
+    /// Grows the dot rightward by moving `to`.
+    pub fn extend_right(&mut self, n: usize) -> Result<(), TextError> {
+        let text = read(&self.text);
+        self.to.move_right_in(&text, n)?;
+        Ok(())
+    }
+
+    pub fn trim_left(&mut self, n: usize) -> Result<(), TextError> {
+        let text = Arc::clone(&self.text);
+        let text = read(&text);
+        self.to.move_right_in(&text, n)?;
+        self.flip_in(&text)
+    }
+
+    pub fn trim_right(&mut self, n: usize) -> Result<(), TextError> {
+        let text = Arc::clone(&self.text);
+        let text = read(&text);
+        self.from.move_left_in(&text, n)?;
+        self.flip_in(&text)
+    }
+
+    pub fn flip(&mut self) -> Result<(), TextError> {
+        let text = Arc::clone(&self.text);
+        let text = read(&text);
+        self.flip_in(&text)
+    }
+
+    fn flip_in(&mut self, text: &Rope) -> Result<(), TextError> {
+        if self.from.as_index_in(text)? > self.to.as_index_in(text)? {
+            std::mem::swap(&mut self.from, &mut self.to);
+        }
+        Ok(())
+    }
+
+    /// Makes the other end of the dot the active one, keeping both ends as
+    /// they are, even marks. Unlike flip, which only ever puts them in order,
+    /// this always swaps.
+    pub fn swap_ends(&mut self) {
+        std::mem::swap(&mut self.from, &mut self.to);
+    }
+
+    /// The span of the dot as char indices, start first whichever way the dot
+    /// points.
+    pub fn as_range(&self) -> Result<Range<usize>, TextError> {
+        let (from, to) = self.indices()?;
+        Ok(from..to)
+    }
+
+    pub fn split_matches(&self, pattern: &str) -> Result<Vec<Dot>, TextError> {
+        let re = self.regex(pattern)?;
+        let text = read(&self.text);
+        let (from, to) = self.indices_in(&text)?;
+        Ok(match_spans(&text, &re, from, to)
+            .into_iter()
+            .map(|(from, to)| self.with_indices(from, to))
+            .collect())
+    }
+
+    /// Like str::split, a delimiter at either end of the dot yields an empty
+    /// dot there, so the pieces always cover the whole span.
+    pub fn split_between(&self, pattern: &str) -> Result<Vec<Dot>, TextError> {
+        let re = self.regex(pattern)?;
+        let text = read(&self.text);
+        let (from, to) = self.indices_in(&text)?;
+        let mut dots = Vec::new();
+        let mut start = from;
+        for (match_from, match_to) in match_spans(&text, &re, from, to) {
+            dots.push(self.with_indices(start, match_from));
+            start = match_to;
+        }
+        dots.push(self.with_indices(start, to));
+        Ok(dots)
+    }
+
+    /// One dot per line the dot touches, clipped to the dot. An empty dot, or
+    /// one within a single line, gives back a single dot over the same span. A
+    /// dot ending right after a newline doesn't touch the next line.
+    pub fn lines(&self) -> Result<Vec<Dot>, TextError> {
+        Ok(self.line_dots()?.into_iter().map(|(_, dot)| dot).collect())
+    }
+
+    /// Like lines, with the buffer line number of each dot, for gutters.
+    pub fn line_dots(&self) -> Result<Vec<(usize, Dot)>, TextError> {
+        let text = read(&self.text);
+        let (from, to) = self.indices_in(&text)?;
+        let mut dots = Vec::new();
+        let mut start = from;
+        let mut line = text.char_to_line(from);
+        loop {
+            let end = text.line_to_char(line + 1).min(to);
+            dots.push((line, self.with_indices(start, end)));
+            if end >= to {
+                return Ok(dots);
+            }
+            start = end;
+            line += 1;
+        }
+    }
+
+    /// sam's s command restricted to dot. The dot ends up covering the
+    /// substituted text.
+    pub fn replace(
+        &mut self,
+        buf: &mut Buffer,
+        pattern: &str,
+        replacement: &str,
+    ) -> Result<usize, TextError> {
+        let re = self.regex(pattern)?;
+        let (count, from, to) = buf.substitute(&re, replacement, &self.from, &self.to)?;
+        self.from.place(from);
+        self.to.place(to);
+        Ok(count)
+    }
+
+    /// A sticky dot's ends are marks, so edits anywhere in the buffer move
+    /// them along with the text around them.
+    pub fn sticky(&mut self, sticky: bool) -> Result<(), TextError> {
+        let text = read(&self.text);
+        let from = self.from.as_index_in(&text)?;
+        let to = self.to.as_index_in(&text)?;
+        span(&text, from.min(to), from.max(to))?;
+        let mut marks = lock(&self.marks);
+        for addr in [&self.from, &self.to] {
+            if let Addr::Mark(mark) = addr {
+                marks.remove(mark);
+            }
+        }
+        (self.from, self.to) = match sticky {
+            true => (Addr::Mark(marks.add(from)), Addr::Mark(marks.add(to))),
+            false => (Addr::Index(from), Addr::Index(to)),
+        };
+        Ok(())
+    }
+
+    // Patterns go through the cache the dot shares with its buffer, so a
+    // search repeated on every keystroke compiles once.
+    fn regex(&self, pattern: &str) -> Result<Regex, TextError> {
+        lock(&self.regexes).get(pattern, SearchOptions::default())
+    }
+
+    fn with_indices(&self, from: usize, to: usize) -> Dot {
+        Dot {
+            text: Arc::clone(&self.text),
+            marks: Arc::clone(&self.marks),
+            regexes: Arc::clone(&self.regexes),
+            from: Addr::Index(from),
+            to: Addr::Index(to),
+            tab_width: self.tab_width,
+            goal: None,
+        }
+    }
+
+    fn indices_in(&self, text: &Rope) -> Result<(usize, usize), TextError> {
+        let from = self.from.as_index_in(text)?;
+        let to = self.to.as_index_in(text)?;
+        span(text, from.min(to), from.max(to))?;
+        Ok((from.min(to), from.max(to)))
+    }
+
+    fn indices(&self) -> Result<(usize, usize), TextError> {
+        self.indices_in(&read(&self.text))
+    }
+}
+
+// Selections only ever hold dots resolved to Addr::Index.
+fn bounds(dot: &Dot) -> (usize, usize) {
+    match (&dot.from, &dot.to) {
+        (Addr::Index(from), Addr::Index(to)) => (*from, *to),
+        _ => unreachable!("selections hold resolved dots"),
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Selections {
+    dots: Vec<Dot>,
+}
+
+impl Selections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All the dots must be on the same buffer.
+    pub fn add(&mut self, dot: Dot) -> Result<(), TextError> {
+        if let Some(first) = self.dots.first()
+            && !Arc::ptr_eq(&first.text, &dot.text)
+        {
+            return Err(TextError::InvalidAddr(
+                "dot is on another buffer".to_string(),
+            ));
+        }
+        let (from, to) = dot.indices()?;
+        let at = self.dots.partition_point(|d| bounds(d) <= (from, to));
+        self.dots.insert(
+            at,
+            Dot {
+                text: dot.text,
+                marks: dot.marks,
+                regexes: dot.regexes,
+                from: Addr::Index(from),
+                to: Addr::Index(to),
+                tab_width: dot.tab_width,
+                goal: None,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.dots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dots.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Dot> {
+        self.dots.iter()
+    }
+
+    pub fn merge(&mut self) {
+        let mut merged: Vec<Dot> = Vec::with_capacity(self.dots.len());
+        for dot in self.dots.drain(..) {
+            let (from, to) = bounds(&dot);
+            if let Some(last) = merged.last_mut() {
+                let (_, last_to) = bounds(last);
+                if from < last_to {
+                    last.to = Addr::Index(last_to.max(to));
+                    continue;
+                }
+            }
+            merged.push(dot);
+        }
+        self.dots = merged;
+    }
+
+    /// Overlapping dots are merged first. The whole replacement is a single
+    /// undo step.
+    pub fn set_all(&mut self, buf: &mut Buffer, s: &str) -> Result<(), TextError> {
+        self.merge();
+        buf.begin_transaction();
+        let ret = self.set_all_in(buf, s);
+        buf.commit_transaction();
+        ret
+    }
+
+    fn set_all_in(&mut self, buf: &mut Buffer, s: &str) -> Result<(), TextError> {
+        let inserted = s.chars().count();
+        let (mut grown, mut shrunk) = (0, 0);
+        for dot in self.dots.iter_mut() {
+            let (from, to) = bounds(dot);
+            let (from, to) = (from + grown - shrunk, to + grown - shrunk);
+            dot.from = Addr::Index(from);
+            dot.to = Addr::Index(to);
+            buf.set_str(dot, s)?;
+            dot.to = Addr::Index(from + inserted);
+            grown += inserted;
+            shrunk += to - from;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+// The text on either side is kept as a rope, so an edit copied from a
+// large selection shares its chunks rather than holding a string copy.
+struct Edit {
+    at: usize,
+    removed: Rope,
+    inserted: Rope,
+}
+
+impl Edit {
+    fn apply(&self, text: &mut Rope) -> Result<(), TextError> {
+        text.try_remove(self.at..self.at + self.removed.len_chars())?;
+        match self.inserted.chunks().nth(1) {
+            None => text.try_insert(self.at, self.inserted.chunks().next().unwrap_or(""))?,
+            Some(_) => {
+                let tail = text.try_split_off(self.at)?;
+                text.append(self.inserted.clone());
+                text.append(tail);
+            }
+        }
+        Ok(())
+    }
+
+    // The single edit turning before into after, found by trimming their
+    // common prefix and suffix. Chunks the two ropes still share, as a rope
+    // and an edited clone of it do, are skipped whole without comparing
+    // their chars.
+    fn between(before: &Rope, after: &Rope) -> Edit {
+        let shared = |a: ropey::iter::Chunks, b: ropey::iter::Chunks| -> usize {
+            a.zip(b)
+                .take_while(|(a, b)| a.as_ptr() == b.as_ptr() && a.len() == b.len())
+                .map(|(a, _)| a.len())
+                .sum()
+        };
+        let (old, new) = (before.len_chars(), after.len_chars());
+        let skip = before.byte_to_char(shared(before.chunks(), after.chunks()));
+        let prefix = skip
+            + before
+                .chars_at(skip)
+                .zip(after.chars_at(skip))
+                .take_while(|(a, b)| a == b)
+                .count();
+        let tail = shared(
+            before.chunks_at_char(old).0.reversed(),
+            after.chunks_at_char(new).0.reversed(),
+        );
+        let skip =
+            (old - before.byte_to_char(before.len_bytes() - tail)).min(old.min(new) - prefix);
+        let suffix = skip
+            + before
+                .chars_at(old - skip)
+                .reversed()
+                .zip(after.chars_at(new - skip).reversed())
+                .take(old.min(new) - prefix - skip)
+                .take_while(|(a, b)| a == b)
+                .count();
+        Edit {
+            at: prefix,
+            removed: Rope::from(before.slice(prefix..old - suffix)),
+            inserted: Rope::from(after.slice(prefix..new - suffix)),
+        }
+    }
+
+    fn inverse(&self) -> Edit {
+        Edit {
+            at: self.at,
+            removed: self.inserted.clone(),
+            inserted: self.removed.clone(),
+        }
+    }
+
+    // Where idx ends up once the edit is applied: positions after the edit
+    // shift by its change in length, positions inside the removed text
+    // collapse onto its start.
+    fn shift(&self, idx: usize) -> usize {
+        let removed = self.removed.len_chars();
+        if idx >= self.at + removed {
+            idx + self.inserted.len_chars() - removed
+        } else if idx > self.at {
+            self.at
+        } else {
+            idx
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeEvent {
+    pub at: usize,
+    pub removed: usize,
+    pub inserted: String,
+}
+
+/// Lines are counted as wc -l would, without the empty one after a trailing
+/// newline. Words follow the Unicode word boundary rules, punctuation aside.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BufferStats {
+    pub chars: usize,
+    pub bytes: usize,
+    pub lines: usize,
+    pub words: usize,
+}
+
+type Observer = Box<dyn FnMut(&ChangeEvent) + Send>;
+
+/// A position that follows the text around it as the buffer is edited.
+#[derive(Clone, Debug)]
+pub struct Mark(Arc<AtomicUsize>);
+
+impl Mark {
+    fn get(&self) -> usize {
+        self.0.load(atomic::Ordering::Relaxed)
+    }
+
+    fn set(&self, idx: usize) {
+        self.0.store(idx, atomic::Ordering::Relaxed);
+    }
+}
+
+// Marks dropped by their owners are pruned on the next edit.
+#[derive(Clone, Default)]
+struct Marks(Vec<Weak<AtomicUsize>>);
+
+impl Marks {
+    fn add(&mut self, idx: usize) -> Mark {
+        let mark = Mark(Arc::new(AtomicUsize::new(idx)));
+        self.0.push(Arc::downgrade(&mark.0));
+        mark
+    }
+
+    fn remove(&mut self, mark: &Mark) {
+        self.0
+            .retain(|m| !std::ptr::eq(m.as_ptr(), Arc::as_ptr(&mark.0)));
+    }
+
+    fn shift(&mut self, edit: &Edit) {
+        self.0.retain(|m| match m.upgrade() {
+            Some(pos) => {
+                let idx = edit.shift(pos.load(atomic::Ordering::Relaxed));
+                pos.store(idx, atomic::Ordering::Relaxed);
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+#[derive(Clone, Default)]
+struct History {
+    undo: Vec<Vec<Edit>>,
+    redo: Vec<Vec<Edit>>,
+    pending: Vec<Edit>,
+    depth: usize,
+}
+
+impl History {
+    fn record_all(&mut self, edits: Vec<Edit>) {
+        if edits.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        if self.depth > 0 {
+            self.pending.extend(edits);
+        } else {
+            self.undo.push(edits);
+        }
+    }
+
+    fn begin(&mut self) {
+        self.depth += 1;
+    }
+
+    fn commit(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        if self.depth == 0 {
+            self.settle();
+        }
+    }
+
+    // Closes the pending edits into an undo step, leaving any transaction
+    // open for the edits to come.
+    fn settle(&mut self) {
+        if !self.pending.is_empty() {
+            self.undo.push(std::mem::take(&mut self.pending));
+        }
+    }
+}
+
+pub struct Buffer {
+    text: Arc<RwLock<Rope>>,
+    history: Arc<Mutex<History>>,
+    marks: Arc<Mutex<Marks>>,
+    observers: Arc<Mutex<Vec<Observer>>>,
+    line_ending: LineEnding,
+    encoding: Encoding,
+    bom: bool,
+    registers: HashMap<char, Register>,
+    dirty: bool,
+    read_only: bool,
+    regexes: Arc<Mutex<RegexCache>>,
+    tab_width: usize,
+}
+
+// Clones are independent buffers, with a copy of the undo history. Use
+// share for another handle to the same text.
+impl Clone for Buffer {
+    fn clone(&self) -> Self {
+        let mut clone = self.snapshot();
+        clone.history = Arc::new(Mutex::new(lock(&self.history).clone()));
+        clone.registers = self.registers.clone();
+        clone
+    }
+}
+
+// Buffers are equal when their text is. Two locks are always taken in
+// address order, so comparing a and b can't deadlock with comparing b and a,
+// and a buffer sharing its text is equal without locking at all.
+impl PartialEq for Buffer {
+    fn eq(&self, other: &Self) -> bool {
+        if Arc::ptr_eq(&self.text, &other.text) {
+            return true;
+        }
+        let (first, second) = match Arc::as_ptr(&self.text) < Arc::as_ptr(&other.text) {
+            true => (&self.text, &other.text),
+            false => (&other.text, &self.text),
+        };
+        let first = read(first);
+        let second = read(second);
+        *first == *second
+    }
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Self::new()
     }
-    pub fn anchor_left(&mut self, anchor: Addr, to: Addr) -> ropey::Result<()> {
-        self.from = Addr::Index(anchor.as_index(self.text)?);
-        self.to = Addr::Index(anchor.as_index(self.text)? + to.as_index(self.text)?);
+}
+
+impl fmt::Display for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = read(&self.text);
+        for chunk in text.chunks() {
+            f.write_str(chunk)?;
+        }
         Ok(())
     }
+}
+
+impl From<&str> for Buffer {
+    fn from(s: &str) -> Self {
+        Self::from_rope(Rope::from_str(s))
+    }
+}
+
+impl FromStr for Buffer {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from(s))
+    }
+}
+
+impl Buffer {
+    pub fn new() -> Self {
+        Self::from_rope(Rope::new())
+    }
+
+    fn from_rope(text: Rope) -> Self {
+        Buffer {
+            line_ending: LineEnding::detect(&text),
+            encoding: Encoding::Utf8,
+            bom: false,
+            registers: HashMap::new(),
+            text: Arc::new(RwLock::new(text)),
+            history: Arc::default(),
+            marks: Arc::default(),
+            observers: Arc::default(),
+            dirty: false,
+            read_only: false,
+            regexes: Arc::default(),
+            tab_width: DEFAULT_TAB_WIDTH,
+        }
+    }
+
+    /// An independent copy of the current text. Ropes share structure, so
+    /// this is cheap however large the buffer is. History, marks, registers
+    /// and subscribers stay with the original.
+    pub fn snapshot(&self) -> Buffer {
+        let mut snapshot = Self::from_rope(read(&self.text).clone());
+        snapshot.line_ending = self.line_ending;
+        snapshot.encoding = self.encoding;
+        snapshot.bom = self.bom;
+        snapshot.dirty = self.dirty;
+        snapshot.read_only = self.read_only;
+        snapshot.tab_width = self.tab_width;
+        snapshot
+    }
+
+    /// Another handle to the same text, marks, subscribers and undo history.
+    /// The history has to be shared too, since an undo made through one handle
+    /// must see the edits made through every other.
+    pub fn share(&self) -> Buffer {
+        Buffer {
+            text: Arc::clone(&self.text),
+            history: Arc::clone(&self.history),
+            marks: Arc::clone(&self.marks),
+            observers: Arc::clone(&self.observers),
+            line_ending: self.line_ending,
+            encoding: self.encoding,
+            bom: self.bom,
+            registers: HashMap::new(),
+            dirty: self.dirty,
+            read_only: self.read_only,
+            regexes: Arc::clone(&self.regexes),
+            tab_width: self.tab_width,
+        }
+    }
+
+    /// Text that isn't UTF-8 fails with InvalidUtf8 rather than an I/O
+    /// error, so the caller can retry with from_bytes and another encoding.
+    pub fn from_reader<T>(reader: T) -> Result<Self, TextError>
+    where
+        T: io::Read,
+    {
+        let mut builder = RopeBuilder::new();
+        read_chunks(reader, |chunk| builder.append(chunk))?;
+        Ok(Self::from_rope(builder.finish()))
+    }
+
+    /// The text is transcoded to UTF-8, and back to the encoding it came in
+    /// when saved, along with any byte order mark it had.
+    pub fn from_bytes(bytes: &[u8], encoding: Encoding) -> Result<Self, TextError> {
+        let (text, encoding, bom) = encoding.decode(bytes)?;
+        let mut buf = Self::from(text.as_str());
+        buf.encoding = encoding;
+        buf.bom = bom;
+        Ok(buf)
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, TextError> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+        Self::from_reader(reader)
+    }
+
+    pub fn save(&mut self, path: &Path) -> Result<(), TextError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_text(&mut writer)?;
+        writer.flush()?;
+        self.mark_clean();
+        Ok(())
+    }
+
+    /// Writes next to the target and renames over it, so a crash mid-write
+    /// never leaves a truncated file behind.
+    pub fn save_atomic(&mut self, path: &Path) -> Result<(), TextError> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+        let mut tmp_name = std::ffi::OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(format!(".{}.tmp", std::process::id()));
+        let tmp = path.with_file_name(tmp_name);
+
+        let file = File::create(&tmp)?;
+        let mut writer = BufWriter::new(&file);
+        let written = self
+            .write_text(&mut writer)
+            .and_then(|_| writer.flush())
+            .and_then(|_| file.sync_all());
+        drop(writer);
+        if let Err(e) = written.and_then(|_| std::fs::rename(&tmp, path)) {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        self.mark_clean();
+        Ok(())
+    }
+
+    /// Writes the text as it is. Unlike save, line endings and encoding are
+    /// left alone.
+    pub fn write_to<W: Write>(&self, writer: W) -> Result<(), TextError> {
+        Ok(read(&self.text).write_to(writer)?)
+    }
+
+    fn write_text(&self, mut writer: impl Write) -> io::Result<()> {
+        let text = read(&self.text);
+        match self.encoding {
+            Encoding::Utf8 => {
+                if self.bom {
+                    writer.write_all("\u{feff}".as_bytes())?;
+                }
+                write_lines(&text, self.line_ending, writer)
+            }
+            encoding => {
+                let mut utf8 = Vec::new();
+                write_lines(&text, self.line_ending, &mut utf8)?;
+                let utf8 = String::from_utf8(utf8).expect("ropes hold valid UTF-8");
+                writer.write_all(&encoding.encode(&utf8)?)
+            }
+        }
+    }
+
+    pub fn len_chars(&self) -> usize {
+        read(&self.text).len_chars()
+    }
+
+    pub fn len_bytes(&self) -> usize {
+        read(&self.text).len_bytes()
+    }
+
+    pub fn len_lines(&self) -> usize {
+        read(&self.text).len_lines()
+    }
+
+    /// Words never span a line break, so they are counted a line at a time.
+    pub fn stats(&self) -> BufferStats {
+        let text = read(&self.text);
+        BufferStats {
+            chars: text.len_chars(),
+            bytes: text.len_bytes(),
+            lines: line_count(&text),
+            words: text
+                .lines()
+                .map(|line| Cow::from(line).unicode_words().count())
+                .sum(),
+        }
+    }
+
+    pub fn line_of(&self, idx: usize) -> Result<usize, TextError> {
+        Ok(read(&self.text).try_char_to_line(idx)?)
+    }
+
+    pub fn offset_of_line(&self, line: usize) -> Result<usize, TextError> {
+        Ok(read(&self.text).try_line_to_char(line)?)
+    }
+
+    /// Runs f with the rope locked, for several reads that must see the same
+    /// text. f must not call back into the buffer or its dots, which would
+    /// deadlock on the same lock.
+    pub fn with_lock<R>(&self, f: impl FnOnce(&Rope) -> R) -> R {
+        f(&read(&self.text))
+    }
+
+    /// Like with_lock, letting f edit the rope directly. Whatever f changes
+    /// is recorded afterwards as one edit spanning the changed text, so undo,
+    /// marks and subscribers still follow along. The change is found by
+    /// comparing the rope with a copy taken before f runs, skipping the
+    /// chunks f left alone, so the cost grows with the buffer's chunk count
+    /// plus the size of the change. Fails with TextError::ReadOnly, without
+    /// running f, on a read-only buffer.
+    pub fn with_lock_mut<R>(&mut self, f: impl FnOnce(&mut Rope) -> R) -> Result<R, TextError> {
+        let mut text = self.edit_text()?;
+        let before = text.clone();
+        let ret = f(&mut text);
+        let edit = Edit::between(&before, &text);
+        if edit.removed.len_chars() == 0 && edit.inserted.len_chars() == 0 {
+            return Ok(ret);
+        }
+        lock(&self.marks).shift(&edit);
+        drop(text);
+        self.record_edits(vec![edit]);
+        Ok(ret)
+    }
+
+    pub fn char_indices(&self) -> CharIndices<'_> {
+        let text = read(&self.text);
+        let end = text.len_chars();
+        CharIndices::new(text, 0, end)
+    }
+
+    /// Lines keep their newline. A buffer ending with a newline has an empty
+    /// last line after it, as ropey counts it.
+    pub fn lines(&self) -> Lines<'_> {
+        Lines {
+            text: read(&self.text),
+        }
+    }
+
+    pub fn line(&self, n: usize) -> Result<DotText<'_>, TextError> {
+        let text = read(&self.text);
+        let from = text.try_line_to_char(n)?;
+        let to = from + line_len(&text, n, true)?;
+        Ok(DotText { text, from, to })
+    }
+
+    /// The text of lines top..top + height, cut short at the last line.
+    pub fn viewport(&self, top: usize, height: usize) -> Result<String, TextError> {
+        let text = read(&self.text);
+        let from = text.try_line_to_char(top)?;
+        let bottom = top.saturating_add(height).min(text.len_lines());
+        Ok(text.slice(from..text.line_to_char(bottom)).to_string())
+    }
+
+    /// Detected when the buffer is loaded. Saving writes every line break
+    /// this way, whatever the buffer holds.
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+
+    pub fn set_line_ending(&mut self, ending: LineEnding) {
+        self.line_ending = ending;
+    }
+
+    /// The tab width dots lay out columns with for vertical moves. A dot
+    /// takes it from the buffer when it is made.
+    pub fn tab_width(&self) -> usize {
+        self.tab_width
+    }
+
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.tab_width = tab_width;
+    }
+
+    /// Every char but a tab is one column wide. A tab runs to the next
+    /// multiple of tab_width.
+    pub fn visual_column(&self, addr: Addr, tab_width: usize) -> Result<usize, TextError> {
+        let text = read(&self.text);
+        let idx = addr.as_index_in(&text)?;
+        span(&text, idx, idx)?;
+        Ok(visual_column(&text, idx, tab_width))
+    }
+
+    /// The char covering the given column of the line, or the end of the
+    /// line's text when the line is shorter.
+    pub fn addr_at_visual_column(
+        &self,
+        line: usize,
+        column: usize,
+        tab_width: usize,
+    ) -> Result<Addr, TextError> {
+        let text = read(&self.text);
+        text.try_line_to_char(line)?;
+        Ok(Addr::Index(index_at_visual_column(
+            &text, line, column, tab_width,
+        )))
+    }
+
+    /// A read-only buffer fails every edit, undo and redo included, with
+    /// TextError::ReadOnly. Reads work as usual.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // Every edit takes the rope through here.
+    fn edit_text(&self) -> Result<RwLockWriteGuard<'_, Rope>, TextError> {
+        match self.read_only {
+            true => Err(TextError::ReadOnly),
+            false => Ok(write(&self.text)),
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    /// Best effort: a reversed dot is read front to back, and one running past
+    /// the end is cut short there.
+    pub fn get(&self, dot: &Dot) -> Result<String, TextError> {
+        let text = read(&dot.text);
+        let len = text.len_chars();
+        let from = dot.from.as_index_in(&text)?.min(len);
+        let to = dot.to.as_index_in(&text)?.min(len);
+        Ok(text.slice(from.min(to)..from.max(to)).to_string())
+    }
+
+    pub fn try_get_strict(&self, dot: &Dot) -> Result<String, TextError> {
+        Ok(dot.text()?.slice().chars().collect())
+    }
+
+    /// Both offsets have to fall on char boundaries.
+    pub fn slice_bytes(&self, start: usize, end: usize) -> Result<String, TextError> {
+        let text = read(&self.text);
+        let from = Addr::Byte(start).as_index_in(&text)?;
+        let to = Addr::Byte(end).as_index_in(&text)?;
+        Ok(span(&text, from, to)?.to_string())
+    }
+
+    pub fn insert(&mut self, at: Addr, s: &str) -> Result<(), TextError> {
+        let mut text = self.edit_text()?;
+        let edit = Edit {
+            at: at.as_index_in(&text)?,
+            removed: Rope::new(),
+            inserted: Rope::from(s),
+        };
+        self.apply_edit(&mut text, &edit)?;
+        drop(text);
+        self.record_edits(vec![edit]);
+        Ok(())
+    }
+
+    /// Breaks the line at `at` with the buffer's line ending. With auto_indent,
+    /// the new line starts with the same spaces and tabs as the one broken.
+    /// Returns a cursor at the start of the new line's text.
+    pub fn insert_newline(&mut self, at: Addr, auto_indent: bool) -> Result<Dot, TextError> {
+        let mut s = self.line_ending.as_str().to_string();
+        let text = read(&self.text);
+        let idx = at.as_index_in(&text)?;
+        span(&text, idx, idx)?;
+        if auto_indent {
+            let start = text.line_to_char(text.char_to_line(idx));
+            s.extend(
+                text.slice(start..idx)
+                    .chars()
+                    .take_while(|c| *c == ' ' || *c == '\t'),
+            );
+        }
+        drop(text);
+        self.insert(Addr::Index(idx), &s)?;
+        let cursor = idx + s.chars().count();
+        Ok(Dot::from_indices(self, cursor, cursor))
+    }
+
+    /// Returns a dot over the pasted text.
+    pub fn paste(&mut self, at: Addr, reg: &Register) -> Result<Dot, TextError> {
+        let idx = at.as_index(&self.text)?;
+        self.insert(Addr::Index(idx), &reg.text.to_string())?;
+        Ok(Dot::from_indices(self, idx, idx + reg.text.len_chars()))
+    }
+
+    pub fn register(&self, name: char) -> Option<&Register> {
+        self.registers.get(&name)
+    }
+
+    pub fn yank_named(&mut self, dot: &Dot, name: char) -> Result<(), TextError> {
+        let mut reg = Register::new();
+        dot.yank(self, &mut reg)?;
+        self.registers.insert(name, reg);
+        Ok(())
+    }
+
+    /// Pasting from an empty register inserts nothing.
+    pub fn paste_named(&mut self, at: Addr, name: char) -> Result<Dot, TextError> {
+        let reg = self.registers.get(&name).cloned().unwrap_or_default();
+        self.paste(at, &reg)
+    }
+
+    pub fn append(&mut self, s: &str) -> Result<Dot, TextError> {
+        let at = self.len_chars();
+        self.insert(Addr::Index(at), s)?;
+        Ok(Dot::from_indices(self, at, at + s.chars().count()))
+    }
+
+    /// Ends a non-empty buffer with a line break, in the buffer's line ending,
+    /// unless it already has one. Returns whether anything was added.
+    pub fn ensure_trailing_newline(&mut self) -> Result<bool, TextError> {
+        let mut text = self.edit_text()?;
+        let len = text.len_chars();
+        if len == 0 || text.char(len - 1) == '\n' {
+            return Ok(false);
+        }
+        let edit = Edit {
+            at: len,
+            removed: Rope::new(),
+            inserted: Rope::from(self.line_ending.as_str()),
+        };
+        self.apply_edit(&mut text, &edit)?;
+        drop(text);
+        self.record_edits(vec![edit]);
+        Ok(true)
+    }
+
+    pub fn prepend(&mut self, s: &str) -> Result<Dot, TextError> {
+        self.insert(Addr::BufferStart, s)?;
+        Ok(Dot::from_indices(self, 0, s.chars().count()))
+    }
+
+    pub fn delete(&mut self, dot: &mut Dot) -> Result<(), TextError> {
+        self.check_dot(dot)?;
+        let mut text = self.edit_text()?;
+        let from = dot.from.as_index_in(&text)?;
+        let to = dot.to.as_index_in(&text)?;
+        let edit = Edit {
+            at: from,
+            removed: Rope::from(span(&text, from, to)?),
+            inserted: Rope::new(),
+        };
+        self.apply_edit(&mut text, &edit)?;
+        drop(text);
+        self.record_edits(vec![edit]);
+        dot.from.place(from);
+        dot.to.place(from);
+        Ok(())
+    }
+
+    /// Removes the line with its line break, clamping to the last line. The
+    /// returned dot is empty at the start of the line taking its place.
+    pub fn delete_line(&mut self, line: usize) -> Result<Dot, TextError> {
+        let text = read(&self.text);
+        let count = line_count(&text);
+        if count == 0 {
+            drop(text);
+            return Ok(Dot::from_indices(self, 0, 0));
+        }
+        let line = line.min(count - 1);
+        let mut from = text.line_to_char(line);
+        let to = text.line_to_char(line + 1);
+        // A last line without a line break takes the one before it instead.
+        let slice = text.line(line);
+        if line > 0 && content_len(slice) == slice.len_chars() {
+            from = text.line_to_char(line - 1) + line_len(&text, line - 1, false)?;
+        }
+        drop(text);
+        self.delete(&mut Dot::from_indices(self, from, to))?;
+        let text = read(&self.text);
+        let idx = text.line_to_char(line.min(line_count(&text).saturating_sub(1)));
+        drop(text);
+        Ok(Dot::from_indices(self, idx, idx))
+    }
+
+    /// Inserts a copy of the line below it. The returned dot is empty at the
+    /// start of the copy.
+    pub fn duplicate_line(&mut self, line: usize) -> Result<Dot, TextError> {
+        let text = read(&self.text);
+        let count = line_count(&text);
+        if line >= count {
+            return Err(ropey::Error::LineIndexOutOfBounds(line, count).into());
+        }
+        let slice = text.line(line);
+        let (at, s) = match content_len(slice) == slice.len_chars() {
+            true => (
+                text.len_chars(),
+                format!("{}{}", self.line_ending.as_str(), slice),
+            ),
+            false => (text.line_to_char(line + 1), slice.to_string()),
+        };
+        drop(text);
+        self.insert(Addr::Index(at), &s)?;
+        let idx = read(&self.text).line_to_char(line + 1);
+        Ok(Dot::from_indices(self, idx, idx))
+    }
+
+    /// Joins the next line onto this one, replacing the line break and the
+    /// next line's indentation with a single space, or with nothing when the
+    /// next line is blank. The returned dot is empty at the join.
+    pub fn join_lines(&mut self, line: usize) -> Result<Dot, TextError> {
+        let text = read(&self.text);
+        let count = line_count(&text);
+        if line + 1 >= count {
+            return Err(ropey::Error::LineIndexOutOfBounds(line + 1, count).into());
+        }
+        let from = text.line_to_char(line) + line_len(&text, line, false)?;
+        let next = text.line(line + 1);
+        let indent = next.chars().take_while(|c| *c == ' ' || *c == '\t').count();
+        let sep = match indent == content_len(next) {
+            true => "",
+            false => " ",
+        };
+        let to = text.line_to_char(line + 1) + indent;
+        drop(text);
+        self.set_str(&mut Dot::from_indices(self, from, to), sep)?;
+        Ok(Dot::from_indices(self, from, from))
+    }
+
+    pub fn add_mark(&mut self, at: Addr) -> Result<Mark, TextError> {
+        let text = read(&self.text);
+        let idx = at.as_index_in(&text)?;
+        span(&text, idx, idx)?;
+        Ok(lock(&self.marks).add(idx))
+    }
+
+    /// A removed mark keeps its last position but no longer follows edits.
+    pub fn resolve(&self, mark: &Mark) -> usize {
+        mark.get()
+    }
+
+    pub fn remove_mark(&mut self, mark: &Mark) {
+        lock(&self.marks).remove(mark);
+    }
+
+    pub fn char_at(&self, addr: Addr) -> Result<char, TextError> {
+        let text = read(&self.text);
+        let idx = addr.as_index_in(&text)?;
+        text.get_char(idx)
+            .ok_or_else(|| ropey::Error::CharIndexOutOfBounds(idx, text.len_chars()).into())
+    }
+
+    /// The index of the bracket balancing the one at `at`, among ()[]{},
+    /// scanning forward from an opener and backward from a closer. Only
+    /// brackets of the same kind count towards nesting.
+    pub fn matching_bracket(&self, at: Addr) -> Result<Option<usize>, TextError> {
+        let text = read(&self.text);
+        let idx = at.as_index_in(&text)?;
+        span(&text, idx, idx)?;
+        let Some(c) = text.get_char(idx) else {
+            return Ok(None);
+        };
+        let (open, close, forward) = match c {
+            '(' => ('(', ')', true),
+            '[' => ('[', ']', true),
+            '{' => ('{', '}', true),
+            ')' => ('(', ')', false),
+            ']' => ('[', ']', false),
+            '}' => ('{', '}', false),
+            _ => return Ok(None),
+        };
+        // Openers count up and closers down either way, so the scan ends
+        // when the count gets back to zero.
+        let chars: Box<dyn Iterator<Item = (usize, char)>> = match forward {
+            true => Box::new((idx..).zip(text.chars_at(idx))),
+            false => Box::new((0..=idx).rev().zip(text.chars_at(idx + 1).reversed())),
+        };
+        let mut depth = 0isize;
+        for (i, c) in chars {
+            match c {
+                c if c == open => depth += 1,
+                c if c == close => depth -= 1,
+                _ => continue,
+            }
+            if depth == 0 {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn char_before(&self, addr: Addr) -> Result<char, TextError> {
+        let text = read(&self.text);
+        let idx = addr.as_index_in(&text)?;
+        idx.checked_sub(1)
+            .and_then(|idx| text.get_char(idx))
+            .ok_or_else(|| ropey::Error::CharIndexOutOfBounds(idx, text.len_chars()).into())
+    }
+
+    pub fn set(&mut self, dot: &mut Dot, s: RopeSlice) -> Result<(), TextError> {
+        self.set_slice(dot, s)
+    }
+
+    /// The slice goes in, and into the undo history, as a rope sharing its
+    /// chunks, so copying a large selection from another buffer never builds
+    /// it up as one string.
+    pub fn set_slice(&mut self, dot: &mut Dot, s: RopeSlice) -> Result<(), TextError> {
+        self.replace(dot, Rope::from(s))
+    }
+
+    pub fn set_str(&mut self, dot: &mut Dot, s: &str) -> Result<(), TextError> {
+        self.replace(dot, Rope::from(s))
+    }
+
+    fn replace(&mut self, dot: &Dot, inserted: Rope) -> Result<(), TextError> {
+        self.check_dot(dot)?;
+        let mut text = self.edit_text()?;
+        let from = dot.from.as_index_in(&text)?;
+        let to = dot.to.as_index_in(&text)?;
+        let edit = Edit {
+            at: from,
+            removed: Rope::from(span(&text, from, to)?),
+            inserted,
+        };
+        self.apply_edit(&mut text, &edit)?;
+        drop(text);
+        self.record_edits(vec![edit]);
+        Ok(())
+    }
+
+    pub fn count_matches(&self, pattern: &str) -> Result<usize, TextError> {
+        self.count_matches_with_options(pattern, SearchOptions::default())
+    }
+
+    pub fn count_matches_with_options(
+        &self,
+        pattern: &str,
+        options: SearchOptions,
+    ) -> Result<usize, TextError> {
+        let re = lock(&self.regexes).get(pattern, options)?;
+        Ok(self.count_matches_re(&re))
+    }
+
+    fn count_matches_re(&self, re: &Regex) -> usize {
+        let text = read(&self.text);
+        re.find_iter(Input::new(text.slice(..))).count()
+    }
+
+    /// Empty matches are kept, at most one per position.
+    pub fn find_all(&self, pattern: &str) -> Result<Vec<Dot>, TextError> {
+        self.find_all_with_options(pattern, SearchOptions::default())
+    }
+
+    pub fn find_all_with_options(
+        &self,
+        pattern: &str,
+        options: SearchOptions,
+    ) -> Result<Vec<Dot>, TextError> {
+        let re = lock(&self.regexes).get(pattern, options)?;
+        Ok(self.find_all_re(&re))
+    }
+
+    fn find_all_re(&self, re: &Regex) -> Vec<Dot> {
+        let text = read(&self.text);
+        match_spans(&text, re, 0, text.len_chars())
+            .into_iter()
+            .map(|(from, to)| Dot::from_indices(self, from, to))
+            .collect()
+    }
+
+    pub fn matcher(&self, pattern: &str) -> Result<Matcher, TextError> {
+        self.matcher_with_options(pattern, SearchOptions::default())
+    }
+
+    pub fn matcher_with_options(
+        &self,
+        pattern: &str,
+        options: SearchOptions,
+    ) -> Result<Matcher, TextError> {
+        let re = lock(&self.regexes).get(pattern, options)?;
+        Ok(Matcher { re })
+    }
+
+    /// The first match starting at or after the end of dot, other than dot
+    /// itself, so repeated calls step through the matches. With wrap, the
+    /// search carries on from the start of the buffer.
+    pub fn find_next(
+        &self,
+        pattern: &str,
+        dot: &Dot,
+        wrap: bool,
+    ) -> Result<Option<Dot>, TextError> {
+        let re = lock(&self.regexes).get(pattern, SearchOptions::default())?;
+        self.find_next_re(&re, dot, wrap)
+    }
+
+    fn find_next_re(&self, re: &Regex, dot: &Dot, wrap: bool) -> Result<Option<Dot>, TextError> {
+        self.check_dot(dot)?;
+        let text = read(&self.text);
+        let (from, to) = dot.indices_in(&text)?;
+        let len = text.len_chars();
+        let found = matches(&text, re, to, len)
+            .find(|m| *m != (from, to))
+            .or_else(|| match wrap {
+                true => matches(&text, re, 0, len).next(),
+                false => None,
+            });
+        Ok(found.map(|(from, to)| Dot::from_indices(self, from, to)))
+    }
+
+    /// The last match ending at or before the start of dot, other than dot
+    /// itself. With wrap, the search carries on from the end of the buffer.
+    pub fn find_prev(
+        &self,
+        pattern: &str,
+        dot: &Dot,
+        wrap: bool,
+    ) -> Result<Option<Dot>, TextError> {
+        let re = lock(&self.regexes).get(pattern, SearchOptions::default())?;
+        self.find_prev_re(&re, dot, wrap)
+    }
+
+    fn find_prev_re(&self, re: &Regex, dot: &Dot, wrap: bool) -> Result<Option<Dot>, TextError> {
+        self.check_dot(dot)?;
+        let text = read(&self.text);
+        let (from, to) = dot.indices_in(&text)?;
+        let len = text.len_chars();
+        let found = matches(&text, re, 0, from)
+            .filter(|m| *m != (from, to))
+            .last()
+            .or_else(|| match wrap {
+                true => matches(&text, re, 0, len).last(),
+                false => None,
+            });
+        Ok(found.map(|(from, to)| Dot::from_indices(self, from, to)))
+    }
+
+    /// Replaces the dot's text with the reader's, gathered a chunk at a time
+    /// into a rope that the buffer and its undo history then share. The
+    /// buffer is only locked once the reader is drained, so a slow reader
+    /// doesn't hold up readers of the buffer, and the reader may read it
+    /// too. On a read error the buffer is left as it was.
+    pub fn set_from_reader<R: io::Read>(
+        &mut self,
+        dot: &mut Dot,
+        reader: R,
+    ) -> Result<(), TextError> {
+        self.check_dot(dot)?;
+        if self.read_only {
+            return Err(TextError::ReadOnly);
+        }
+        let mut inserted = RopeBuilder::new();
+        read_chunks(reader, |chunk| inserted.append(chunk))?;
+        let mut text = self.edit_text()?;
+        let (from, to) = dot.indices_in(&text)?;
+        let edit = Edit {
+            at: from,
+            removed: Rope::from(text.slice(from..to)),
+            inserted: inserted.finish(),
+        };
+        self.apply_edit(&mut text, &edit)?;
+        drop(text);
+        let end = from + edit.inserted.len_chars();
+        self.record_edits(vec![edit]);
+        dot.from.place(from);
+        dot.to.place(end);
+        Ok(())
+    }
+
+    pub fn replace_all(&mut self, pattern: &str, replacement: &str) -> Result<usize, TextError> {
+        self.replace_all_with_options(pattern, replacement, SearchOptions::default())
+    }
+
+    pub fn replace_all_with_options(
+        &mut self,
+        pattern: &str,
+        replacement: &str,
+        options: SearchOptions,
+    ) -> Result<usize, TextError> {
+        let re = lock(&self.regexes).get(pattern, options)?;
+        let (count, ..) =
+            self.substitute(&re, replacement, &Addr::BufferStart, &Addr::BufferEnd)?;
+        Ok(count)
+    }
+
+    // Replaces every match within from..to under one lock, back to front so
+    // the spans found up front stay valid, as a single undo step. Returns the
+    // number of replacements and the span the text now covers.
+    fn substitute(
+        &mut self,
+        re: &Regex,
+        replacement: &str,
+        from: &Addr,
+        to: &Addr,
+    ) -> Result<(usize, usize, usize), TextError> {
+        let mut text = self.edit_text()?;
+        let (start, to) = (from.as_index_in(&text)?, to.as_index_in(&text)?);
+        span(&text, start, to)?;
+        let subs = substitutions(&text, re, start, to, replacement);
+        let mut edits = Vec::with_capacity(subs.len());
+        let mut end = to;
+        for (from, to, inserted) in subs.into_iter().rev() {
+            let edit = Edit {
+                at: from,
+                removed: Rope::from(text.slice(from..to)),
+                inserted: Rope::from(inserted),
+            };
+            self.apply_edit(&mut text, &edit)?;
+            end = end + edit.inserted.len_chars() - (to - from);
+            edits.push(edit);
+        }
+        drop(text);
+        let count = edits.len();
+        self.record_edits(edits);
+        Ok((count, start, end))
+    }
+
+    /// The edits turning this buffer into other, line by line, as dots over
+    /// this buffer with their new text, ready for apply_edits. Lines common
+    /// to both ends are skipped, and the rest is matched up through their
+    /// longest common subsequence, found with Myers' linear space diff. The
+    /// time grows with the size of the changed region times the number of
+    /// lines that differ, and the memory only with the size of the region.
+    pub fn diff(&self, other: &Buffer) -> Vec<(Dot, String)> {
+        if Arc::ptr_eq(&self.text, &other.text) {
+            return Vec::new();
+        }
+        // A copy, so the two buffers are never locked at once.
+        let theirs = read(&other.text).clone();
+        let mine = read(&self.text);
+        let a: Vec<_> = mine.lines().collect();
+        let b: Vec<_> = theirs.lines().collect();
+        let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+        let suffix = a[prefix..]
+            .iter()
+            .rev()
+            .zip(b[prefix..].iter().rev())
+            .take_while(|(x, y)| x == y)
+            .count();
+        let (a, b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+        // Lines are compared as ids, so each is hashed once rather than
+        // compared char by char on every step of the search.
+        let mut ids = HashMap::new();
+        let lines: Vec<u32> = a
+            .iter()
+            .chain(b)
+            .map(|&line| {
+                let next = ids.len() as u32;
+                *ids.entry(line).or_insert(next)
+            })
+            .collect();
+        let (a, b) = lines.split_at(a.len());
+        let mut common = Vec::new();
+        common_items(a, b, (0, 0), &mut common);
+
+        let mut hunks = Vec::new();
+        let mut start = (0, 0);
+        for (i, j) in common.into_iter().chain([(a.len(), b.len())]) {
+            if (i, j) != start {
+                hunks.push((start, (i, j)));
+            }
+            start = (i + 1, j + 1);
+        }
+
+        let (from_char, to_char) = (
+            |n| mine.line_to_char(prefix + n),
+            |n| theirs.line_to_char(prefix + n),
+        );
+        hunks
+            .into_iter()
+            .map(|((i0, j0), (i1, j1))| {
+                let dot = Dot::from_indices(self, from_char(i0), from_char(i1));
+                (dot, theirs.slice(to_char(j0)..to_char(j1)).to_string())
+            })
+            .collect()
+    }
+
+    /// Sets the text of each dot, as from a formatter or a language server,
+    /// in one undo step. The dots must not overlap, and nothing is edited if
+    /// they do. Empty dots at the same place insert in the order given.
+    pub fn apply_edits(&mut self, edits: Vec<(Dot, String)>) -> Result<(), TextError> {
+        let mut text = self.edit_text()?;
+        let mut spans = Vec::with_capacity(edits.len());
+        for (dot, s) in edits {
+            self.check_dot(&dot)?;
+            let (from, to) = dot.indices_in(&text)?;
+            spans.push((from, to, s));
+        }
+        spans.sort_by_key(|(from, to, _)| (*from, *to));
+        if spans.windows(2).any(|w| w[0].1 > w[1].0) {
+            return Err(TextError::InvalidAddr("dots overlap".to_string()));
+        }
+        let mut applied = Vec::with_capacity(spans.len());
+        for (from, to, inserted) in spans.into_iter().rev() {
+            let edit = Edit {
+                at: from,
+                removed: Rope::from(text.slice(from..to)),
+                inserted: Rope::from(inserted),
+            };
+            self.apply_edit(&mut text, &edit)?;
+            applied.push(edit);
+        }
+        drop(text);
+        self.record_edits(applied);
+        Ok(())
+    }
+
+    /// Types s at every cursor, the `to` end of each dot, as one undo step.
+    /// Inserts go from the last cursor back so the earlier ones stay valid,
+    /// and cursors sharing a position get a single insert. Each dot is then
+    /// left empty just after its text.
+    pub fn insert_at_each(&mut self, dots: &mut [Dot], s: &str) -> Result<(), TextError> {
+        if s.is_empty() {
+            return Ok(());
+        }
+        let mut text = self.edit_text()?;
+        let mut at = Vec::with_capacity(dots.len());
+        for dot in dots.iter() {
+            self.check_dot(dot)?;
+            let idx = dot.to.as_index_in(&text)?;
+            span(&text, idx, idx)?;
+            at.push(idx);
+        }
+        let mut positions = at.clone();
+        positions.sort_unstable();
+        positions.dedup();
+        let mut edits = Vec::with_capacity(positions.len());
+        for &idx in positions.iter().rev() {
+            let edit = Edit {
+                at: idx,
+                removed: Rope::new(),
+                inserted: Rope::from(s),
+            };
+            self.apply_edit(&mut text, &edit)?;
+            edits.push(edit);
+        }
+        drop(text);
+        self.record_edits(edits);
+        let len = s.chars().count();
+        for (dot, idx) in dots.iter_mut().zip(at) {
+            let end = idx + len * positions.partition_point(|p| *p <= idx);
+            dot.from.place(end);
+            dot.to.place(end);
+        }
+        Ok(())
+    }
+
+    /// Swaps the text of two dots that don't overlap, as one undo step. Each
+    /// dot then covers the text it was given.
+    pub fn transpose(&mut self, a: &mut Dot, b: &mut Dot) -> Result<(), TextError> {
+        self.check_dot(a)?;
+        self.check_dot(b)?;
+        let mut text = self.edit_text()?;
+        let (a_from, a_to) = a.indices_in(&text)?;
+        let (b_from, b_to) = b.indices_in(&text)?;
+        if a_from < b_to && b_from < a_to {
+            return Err(TextError::InvalidAddr("dots overlap".to_string()));
+        }
+        let (first, second) = match a_from <= b_from {
+            true => ((a_from, a_to), (b_from, b_to)),
+            false => ((b_from, b_to), (a_from, a_to)),
+        };
+        let first_text = Rope::from(text.slice(first.0..first.1));
+        let second_text = Rope::from(text.slice(second.0..second.1));
+        // the later span first, so the earlier one stays put
+        let edits = vec![
+            Edit {
+                at: second.0,
+                removed: second_text.clone(),
+                inserted: first_text.clone(),
+            },
+            Edit {
+                at: first.0,
+                removed: first_text,
+                inserted: second_text,
+            },
+        ];
+        for edit in edits.iter() {
+            self.apply_edit(&mut text, edit)?;
+        }
+        drop(text);
+        self.record_edits(edits);
+
+        let (first_len, second_len) = (first.1 - first.0, second.1 - second.0);
+        let moved = second.0 + second_len - first_len;
+        let (first, second) = ((moved, moved + first_len), (first.0, first.0 + second_len));
+        let (a_span, b_span) = match a_from <= b_from {
+            true => (first, second),
+            false => (second, first),
+        };
+        a.from.place(a_span.0);
+        a.to.place(a_span.1);
+        b.from.place(b_span.0);
+        b.to.place(b_span.1);
+        Ok(())
+    }
+
+    pub fn uppercase(&mut self, dot: &mut Dot) -> Result<(), TextError> {
+        self.transform(dot, str::to_uppercase)
+    }
+
+    pub fn lowercase(&mut self, dot: &mut Dot) -> Result<(), TextError> {
+        self.transform(dot, str::to_lowercase)
+    }
+
+    /// Uppercases the first char of each word and lowercases the rest.
+    pub fn titlecase(&mut self, dot: &mut Dot) -> Result<(), TextError> {
+        self.transform(dot, |s| {
+            let mut title = String::with_capacity(s.len());
+            let mut in_word = false;
+            for c in s.chars() {
+                match (is_word_char(c), in_word) {
+                    (true, false) => title.extend(c.to_uppercase()),
+                    (true, true) => title.extend(c.to_lowercase()),
+                    (false, _) => title.push(c),
+                }
+                in_word = is_word_char(c);
+            }
+            title
+        })
+    }
+
+    // Case mapping can change the length, as ß uppercases to SS, so the dot
+    // is refitted to the result.
+    fn transform(&mut self, dot: &mut Dot, f: impl Fn(&str) -> String) -> Result<(), TextError> {
+        self.check_dot(dot)?;
+        let mut text = self.edit_text()?;
+        let (from, to) = dot.indices_in(&text)?;
+        let removed = text.slice(from..to).to_string();
+        let inserted = f(&removed);
+        if inserted == removed {
+            return Ok(());
+        }
+        let edit = Edit {
+            at: from,
+            removed: Rope::from(removed),
+            inserted: Rope::from(inserted),
+        };
+        self.apply_edit(&mut text, &edit)?;
+        drop(text);
+        dot.from.place(from);
+        dot.to.place(from + edit.inserted.len_chars());
+        self.record_edits(vec![edit]);
+        Ok(())
+    }
+
+    /// Prefixes unit to each non-blank line the dot touches.
+    pub fn indent(&mut self, dot: &mut Dot, unit: &str) -> Result<(), TextError> {
+        self.edit_lines(dot, |line, at| match content_len(line) {
+            0 => None,
+            _ => Some(Edit {
+                at,
+                removed: Rope::new(),
+                inserted: Rope::from(unit),
+            }),
+        })
+    }
+
+    /// Removes unit from the start of each line the dot touches, or as much
+    /// leading whitespace as a unit would be in chars.
+    pub fn dedent(&mut self, dot: &mut Dot, unit: &str) -> Result<(), TextError> {
+        let len = unit.chars().count();
+        self.edit_lines(dot, |line, at| {
+            let n = match line.chars().take(len).eq(unit.chars()) {
+                true => len,
+                false => line
+                    .chars()
+                    .take(len)
+                    .take_while(|c| *c == ' ' || *c == '\t')
+                    .count(),
+            };
+            (n > 0).then(|| Edit {
+                at,
+                removed: Rope::from(line.slice(..n)),
+                inserted: Rope::new(),
+            })
+        })
+    }
+
+    /// Removes spaces and tabs from the end of every line, leaving the line
+    /// breaks alone, as one undo step. Returns how many chars went.
+    pub fn trim_trailing_whitespace(&mut self) -> Result<usize, TextError> {
+        let trimmed = Cell::new(0);
+        self.edit_lines(&mut Dot::new(self), |line, at| {
+            let len = content_len(line);
+            let n = line
+                .chars_at(len)
+                .reversed()
+                .take_while(|c| *c == ' ' || *c == '\t')
+                .count();
+            trimmed.set(trimmed.get() + n);
+            (n > 0).then(|| Edit {
+                at: at + len - n,
+                removed: Rope::from(line.slice(len - n..len)),
+                inserted: Rope::new(),
+            })
+        })
+        .map(|()| trimmed.get())
+    }
+
+    /// Inserts prefix after the indentation of each line the dot touches,
+    /// skipping blank lines. The dot then covers those lines whole.
+    pub fn comment_lines(&mut self, dot: &mut Dot, prefix: &str) -> Result<(), TextError> {
+        self.edit_lines(dot, |line, at| {
+            let indent = indent_len(line);
+            (indent < content_len(line)).then(|| Edit {
+                at: at + indent,
+                removed: Rope::new(),
+                inserted: Rope::from(prefix),
+            })
+        })
+    }
+
+    /// Removes prefix after the indentation of each line the dot touches, or
+    /// prefix without its trailing whitespace, so "// " also undoes "//".
+    pub fn uncomment_lines(&mut self, dot: &mut Dot, prefix: &str) -> Result<(), TextError> {
+        self.edit_lines(dot, |line, at| {
+            let indent = indent_len(line);
+            let len = comment_len(line.slice(indent..), prefix);
+            (len > 0).then(|| Edit {
+                at: at + indent,
+                removed: Rope::from(line.slice(indent..indent + len)),
+                inserted: Rope::new(),
+            })
+        })
+    }
+
+    /// Comments the lines out unless every non-blank one already is.
+    pub fn toggle_comment(&mut self, dot: &mut Dot, prefix: &str) -> Result<(), TextError> {
+        self.check_dot(dot)?;
+        let text = read(&self.text);
+        let (from, to) = dot.indices_in(&text)?;
+        let (first, last) = line_range(&text, from, to);
+        let commented = (first..=last).map(|n| text.line(n)).all(|line| {
+            let indent = indent_len(line);
+            indent == content_len(line) || comment_len(line.slice(indent..), prefix) > 0
+        });
+        drop(text);
+        match commented {
+            true => self.uncomment_lines(dot, prefix),
+            false => self.comment_lines(dot, prefix),
+        }
+    }
+
+    /// Sorts the lines the dot touches. Each line break stays where it was,
+    /// so a last line without one still lacks one after the sort.
+    pub fn sort_lines(&mut self, dot: &Dot, descending: bool) -> Result<(), TextError> {
+        match descending {
+            true => self.sort_lines_by(dot, |a, b| b.cmp(a)),
+            false => self.sort_lines_by(dot, |a, b| a.cmp(b)),
+        }
+    }
+
+    pub fn sort_lines_by(
+        &mut self,
+        dot: &Dot,
+        mut cmp: impl FnMut(&str, &str) -> Ordering,
+    ) -> Result<(), TextError> {
+        self.rewrite_lines(dot, |lines| {
+            let mut contents: Vec<_> = lines.iter_mut().map(|(s, _)| std::mem::take(s)).collect();
+            contents.sort_by(|a, b| cmp(a, b));
+            for ((s, _), content) in lines.iter_mut().zip(contents) {
+                *s = content;
+            }
+        })
+    }
+
+    /// Removes lines repeating the one above them among those the dot
+    /// touches, as uniq does, and returns how many went.
+    pub fn unique_lines(&mut self, dot: &Dot) -> Result<usize, TextError> {
+        let mut removed = 0;
+        self.rewrite_lines(dot, |lines| {
+            let len = lines.len();
+            let mut kept: Vec<(String, String)> = Vec::with_capacity(len);
+            for (s, ending) in lines.drain(..) {
+                match kept.last_mut() {
+                    // The last line's break, or lack of one, is kept.
+                    Some(last) if last.0 == s => last.1 = ending,
+                    _ => kept.push((s, ending)),
+                }
+            }
+            removed = len - kept.len();
+            *lines = kept;
+        })?;
+        Ok(removed)
+    }
+
+    // Replaces the lines the dot touches, split into text and line break,
+    // with what f leaves of them, as one edit.
+    fn rewrite_lines(
+        &mut self,
+        dot: &Dot,
+        f: impl FnOnce(&mut Vec<(String, String)>),
+    ) -> Result<(), TextError> {
+        self.check_dot(dot)?;
+        let mut text = self.edit_text()?;
+        let (from, to) = dot.indices_in(&text)?;
+        let (first, last) = line_range(&text, from, to);
+        let from = text.line_to_char(first);
+        let to = text.line_to_char(last + 1);
+        let mut lines: Vec<_> = (first..=last)
+            .map(|n| {
+                let line = text.line(n);
+                let len = content_len(line);
+                (line.slice(..len).to_string(), line.slice(len..).to_string())
+            })
+            .collect();
+        f(&mut lines);
+        let inserted: String = lines
+            .iter()
+            .flat_map(|(s, e)| [s.as_str(), e.as_str()])
+            .collect();
+        let removed = text.slice(from..to);
+        if removed == inserted.as_str() {
+            return Ok(());
+        }
+        let edit = Edit {
+            at: from,
+            removed: Rope::from(removed),
+            inserted: Rope::from(inserted),
+        };
+        self.apply_edit(&mut text, &edit)?;
+        drop(text);
+        self.record_edits(vec![edit]);
+        Ok(())
+    }
+
+    // Edits the start of each line the dot touches, bottom-up so the line
+    // starts found stay valid, as one undo step. The dot then covers those
+    // lines whole.
+    fn edit_lines(
+        &mut self,
+        dot: &mut Dot,
+        edit: impl Fn(RopeSlice, usize) -> Option<Edit>,
+    ) -> Result<(), TextError> {
+        self.check_dot(dot)?;
+        let mut text = self.edit_text()?;
+        let (from, to) = dot.indices_in(&text)?;
+        let (first, last) = line_range(&text, from, to);
+        let mut edits = Vec::new();
+        for line in (first..=last).rev() {
+            let start = text.line_to_char(line);
+            if let Some(edit) = edit(text.line(line), start) {
+                self.apply_edit(&mut text, &edit)?;
+                edits.push(edit);
+            }
+        }
+        let from = text.line_to_char(first);
+        let to = Addr::LineEnd(last).as_index_in(&text)?;
+        drop(text);
+        self.record_edits(edits);
+        dot.from.place(from);
+        dot.to.place(to);
+        Ok(())
+    }
+
+    /// Subscribers are called after every edit, with the buffer unlocked. They
+    /// are shared with handles from share, along with the text, but not with
+    /// clones, which start with no subscribers.
+    pub fn on_change(&mut self, f: impl FnMut(&ChangeEvent) + Send + 'static) {
+        lock(&self.observers).push(Box::new(f));
+    }
+
+    // A dot resolves against its own text, so one from another buffer would
+    // otherwise edit this one at offsets that mean nothing here.
+    fn check_dot(&self, dot: &Dot) -> Result<(), TextError> {
+        match Arc::ptr_eq(&dot.text, &self.text) {
+            true => Ok(()),
+            false => Err(TextError::InvalidAddr(
+                "dot is on another buffer".to_string(),
+            )),
+        }
+    }
+
+    // Applies the edit with the text still locked, carrying the marks past
+    // it, so no reader sees one moved without the other.
+    fn apply_edit(&self, text: &mut Rope, edit: &Edit) -> Result<(), TextError> {
+        edit.apply(text)?;
+        lock(&self.marks).shift(edit);
+        Ok(())
+    }
+
+    // Once the text is unlocked, tells subscribers about the applied edits
+    // and records them as one undo step.
+    fn record_edits(&mut self, edits: Vec<Edit>) {
+        if edits.is_empty() {
+            return;
+        }
+        self.notify(&edits);
+        lock(&self.history).record_all(edits);
+        self.dirty = true;
+    }
+
+    fn notify<'e>(&self, edits: impl IntoIterator<Item = &'e Edit>) {
+        let mut observers = lock(&self.observers);
+        if observers.is_empty() {
+            return;
+        }
+        for edit in edits {
+            let event = ChangeEvent {
+                at: edit.at,
+                removed: edit.removed.len_chars(),
+                inserted: edit.inserted.to_string(),
+            };
+            for f in observers.iter_mut() {
+                f(&event);
+            }
+        }
+    }
+
+    /// Nested transactions are flattened into the outermost one.
+    pub fn begin_transaction(&mut self) {
+        lock(&self.history).begin();
+    }
+
+    pub fn commit_transaction(&mut self) {
+        lock(&self.history).commit();
+    }
+
+    pub fn undo(&mut self) -> Result<Option<Dot>, TextError> {
+        self.step(true)
+    }
+
+    pub fn redo(&mut self) -> Result<Option<Dot>, TextError> {
+        self.step(false)
+    }
+
+    // Moves the latest step from the undo stack to the redo stack, applying
+    // its inverse, or back the other way. Edits made so far in an open
+    // transaction become a step of their own first, so they are never
+    // committed later with offsets the undo has moved.
+    fn step(&mut self, back: bool) -> Result<Option<Dot>, TextError> {
+        // Checked before the history is popped, so nothing is lost.
+        if self.read_only {
+            return Err(TextError::ReadOnly);
+        }
+        let mut history = lock(&self.history);
+        history.settle();
+        let popped = match back {
+            true => history.undo.pop(),
+            false => history.redo.pop(),
+        };
+        drop(history);
+        let Some(edits) = popped else {
+            return Ok(None);
+        };
+        let applied: Vec<Edit> = match back {
+            true => edits.iter().rev().map(Edit::inverse).collect(),
+            false => edits.clone(),
+        };
+        let mut text = self.edit_text()?;
+        for edit in applied.iter() {
+            self.apply_edit(&mut text, edit)?;
+        }
+        drop(text);
+        self.notify(&applied);
+        match back {
+            true => lock(&self.history).redo.push(edits),
+            false => lock(&self.history).undo.push(edits),
+        }
+        self.dirty = true;
+        let (from, to) = applied.last().map_or((0, 0), |edit| {
+            (edit.at, edit.at + edit.inserted.len_chars())
+        });
+        Ok(Some(Dot::from_indices(self, from, to)))
+    }
+}
+
+/// A buffer meant to be shared between threads. Cloning it gives another
+/// handle to the same buffer, where cloning a Buffer copies it. The buffer's
+/// own lock is taken before its rope's, so the two can't deadlock.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Arc<RwLock<Buffer>>);
+
+impl SharedBuffer {
+    pub fn new(buf: Buffer) -> SharedBuffer {
+        SharedBuffer(Arc::new(RwLock::new(buf)))
+    }
+
+    pub fn lock_read(&self) -> RwLockReadGuard<'_, Buffer> {
+        read(&self.0)
+    }
+
+    pub fn lock_write(&self) -> RwLockWriteGuard<'_, Buffer> {
+        write(&self.0)
+    }
+}
+
+impl From<Buffer> for SharedBuffer {
+    fn from(buf: Buffer) -> Self {
+        SharedBuffer::new(buf)
+    }
+}
+
+/// Parses a sam-style address into a dot:
+///
+/// ```text
+///   n        line n, counting from 1, with its newline. 0 is the empty
+///            point at the start of the buffer.
+///   $        the empty point at the end of the buffer.
+///   .        the empty point at the start of the buffer, since a buffer
+///            has no dot of its own.
+///   /re/     the next match after ., wrapping around. ?re? is the previous
+///            match and searches backward.
+///   a+n a-n  the n-th line after the end of a, or before its start. n
+///            defaults to 1 and a to ., and n can also be /re/ or ?re? to
+///            search from a.
+///   a,b      from the start of a to the end of b. a defaults to 0 and b to
+///            $, so "," is the whole buffer.
+/// ```
+///
+/// `+` and `-` bind tighter than `,`, so `1,.+5` reads as `1,(.+5)`.
+pub fn parse_address(buf: &Buffer, expr: &str) -> Result<Dot, TextError> {
+    let text = read(&buf.text);
+    let (from, to) = AddressParser {
+        text: &text,
+        regexes: &buf.regexes,
+        expr,
+        pos: 0,
+    }
+    .parse()?;
+    drop(text);
+    Ok(Dot::from_indices(buf, from, to))
+}
+
+struct AddressParser<'a> {
+    text: &'a Rope,
+    regexes: &'a Mutex<RegexCache>,
+    expr: &'a str,
+    pos: usize,
+}
+
+impl AddressParser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.expr[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn error(&self, msg: &str) -> TextError {
+        TextError::InvalidAddr(format!("{msg} at {} in {:?}", self.pos, self.expr))
+    }
+
+    fn parse(&mut self) -> Result<(usize, usize), TextError> {
+        let left = self.simple()?;
+        let span = match self.peek() {
+            Some(',') => {
+                self.bump();
+                let len = self.text.len_chars();
+                let from = left.unwrap_or((0, 0));
+                let to = self.simple()?.unwrap_or((len, len));
+                if to.1 < from.0 {
+                    return Err(self.error("addresses out of order"));
+                }
+                (from.0, to.1)
+            }
+            _ => left.ok_or_else(|| self.error("missing address"))?,
+        };
+        match self.peek() {
+            None => Ok(span),
+            Some(c) => Err(self.error(&format!("unexpected {c:?}"))),
+        }
+    }
+
+    fn simple(&mut self) -> Result<Option<(usize, usize)>, TextError> {
+        let mut span = match self.peek() {
+            Some('0'..='9') => {
+                let n = self.number()?;
+                Some(self.line(n)?)
+            }
+            Some('$') => {
+                self.bump();
+                let len = self.text.len_chars();
+                Some((len, len))
+            }
+            Some('.') => {
+                self.bump();
+                Some((0, 0))
+            }
+            Some('/') => Some(self.search((0, 0), true)?),
+            Some('?') => Some(self.search((0, 0), false)?),
+            _ => None,
+        };
+        while let Some(sign @ ('+' | '-')) = self.peek() {
+            self.bump();
+            let base = span.unwrap_or((0, 0));
+            let forward = sign == '+';
+            span = Some(match self.peek() {
+                Some('/' | '?') => self.search(base, forward)?,
+                Some('0'..='9') => {
+                    let n = self.number()?;
+                    self.offset(base, n, forward)?
+                }
+                _ => self.offset(base, 1, forward)?,
+            });
+        }
+        Ok(span)
+    }
+
+    fn number(&mut self) -> Result<usize, TextError> {
+        let start = self.pos;
+        while let Some('0'..='9') = self.peek() {
+            self.bump();
+        }
+        self.expr[start..self.pos]
+            .parse()
+            .map_err(|_| self.error("line number too large"))
+    }
+
+    fn line(&self, n: usize) -> Result<(usize, usize), TextError> {
+        let text = self.text;
+        match n {
+            0 => Ok((0, 0)),
+            n if n > line_count(text) => Err(self.error(&format!("line {n} out of range"))),
+            n => Ok((text.line_to_char(n - 1), text.line_to_char(n))),
+        }
+    }
+
+    // Counts lines from the one holding the end of base, or its start when
+    // going backward. An end at the start of a line belongs to the line
+    // before, so 3+1 is line 4.
+    fn offset(
+        &self,
+        base: (usize, usize),
+        n: usize,
+        forward: bool,
+    ) -> Result<(usize, usize), TextError> {
+        let text = self.text;
+        let line = match forward {
+            true => {
+                let line = text.char_to_line(base.1);
+                match text.line_to_char(line) == base.1 {
+                    true => line.checked_add(n),
+                    false => (line + 1).checked_add(n),
+                }
+            }
+            false => (text.char_to_line(base.0) + 1).checked_sub(n),
+        };
+        match line {
+            Some(line) => self.line(line),
+            None => Err(self.error("line out of range")),
+        }
+    }
+
+    // Reads a pattern up to its closing delimiter, which may be left off at
+    // the end of the expression, and searches from base.
+    fn search(&mut self, base: (usize, usize), forward: bool) -> Result<(usize, usize), TextError> {
+        let delim = self.bump().expect("called on a delimiter");
+        let mut pattern = String::new();
+        loop {
+            match self.bump() {
+                None => break,
+                Some(c) if c == delim => break,
+                Some('\\') => match self.bump() {
+                    Some(c) if c == delim => pattern.push(c),
+                    Some(c) => {
+                        pattern.push('\\');
+                        pattern.push(c);
+                    }
+                    None => pattern.push('\\'),
+                },
+                Some(c) => pattern.push(c),
+            }
+        }
+        let text = self.text;
+        let re = lock(self.regexes).get(&pattern, SearchOptions::default())?;
+        if forward {
+            return match regex_span(text, &re, &pattern, base.1) {
+                Err(TextError::NoMatch(_)) => regex_span(text, &re, &pattern, 0),
+                found => found,
+            };
+        }
+        matches(text, &re, 0, base.0)
+            .last()
+            .or_else(|| matches(text, &re, 0, text.len_chars()).last())
+            .ok_or(TextError::NoMatch(pattern))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_from_str() {
+        let buf: Buffer = "A minimal\ntext editor.".parse().unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::BufferStart, Addr::BufferEnd).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "A minimal\ntext editor.");
+        assert_eq!(Buffer::from("").len_chars(), 0);
+    }
+
+    #[test]
+    fn test_buffer_eq() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut other = Buffer::from("Hello there !\n");
+        assert!(buf != other);
+        other
+            .append("How are you ?\nI test a text editor.\n")
+            .unwrap();
+        assert!(buf == other);
+        assert!(other == buf);
+        assert!(buf == buf.share());
+        assert!(Buffer::new() == Buffer::default());
+    }
+
+    #[test]
+    fn test_buffer_stats() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let stats = BufferStats {
+            chars: 50,
+            bytes: 50,
+            lines: 3,
+            words: 10,
+        };
+        assert_eq!(buf.stats(), stats);
+        let stats = Buffer::from("né à Noël, l'été").stats();
+        assert_eq!((stats.chars, stats.bytes, stats.lines), (16, 21, 1));
+        assert_eq!(stats.words, 4);
+    }
+
+    #[test]
+    fn test_buffer_write_to() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut out = Vec::new();
+        buf.write_to(&mut out).unwrap();
+        assert_eq!(out, std::fs::read("tests/test.txt").unwrap());
+    }
+
+    #[test]
+    fn test_buffer_display() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let contents = std::fs::read_to_string("tests/test.txt").unwrap();
+        assert_eq!(buf.to_string(), contents);
+        assert_eq!(Buffer::new().to_string(), "");
+    }
+
+    #[test]
+    fn test_addr_byte() {
+        let text = Rope::from_str("né à Noël\n");
+        assert_eq!(Addr::Byte(0).as_index_in(&text).unwrap(), 0);
+        assert_eq!(Addr::Byte(3).as_index_in(&text).unwrap(), 2);
+        assert_eq!(Addr::Byte(11).as_index_in(&text).unwrap(), 8);
+        assert_eq!(Addr::Byte(11).as_coordinates_in(&text).unwrap(), (0, 8));
+        assert_eq!(Addr::Byte(13).as_index_in(&text).unwrap(), 10);
+        assert!(Addr::Byte(2).as_index_in(&text).is_err());
+        assert!(Addr::Byte(10).as_index_in(&text).is_err());
+        assert!(Addr::Byte(14).as_index_in(&text).is_err());
+    }
+
+    #[test]
+    fn test_addr_line_start_non_blank() {
+        let buf = Buffer::from("fn main() {\n  \t let a = 1;\n \t\n}");
+        let at = |line| Addr::LineStartNonBlank(line).as_index(&buf.text).unwrap();
+        assert_eq!(at(0), 0);
+        assert_eq!(at(1), 16);
+        assert_eq!(at(2), 27);
+        assert_eq!(at(3), 30);
+        assert_eq!(
+            Addr::LineStartNonBlank(1)
+                .as_coordinates(&buf.text)
+                .unwrap(),
+            (1, 4)
+        );
+        assert!(Addr::LineStartNonBlank(4).as_index(&buf.text).is_err());
+    }
+
+    #[test]
+    fn test_addr_percent() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let at = |pct| Addr::Percent(pct).as_index(&buf.text).unwrap();
+        assert_eq!(at(0), 0);
+        // 25 of 50 chars is nearer line 2 (28) than line 1 (14)
+        assert_eq!(at(50), 28);
+        assert_eq!(at(30), 14);
+        assert_eq!(at(100), 28);
+        assert_eq!(at(255), 28);
+        let buf = Buffer::from("");
+        assert_eq!(Addr::Percent(50).as_index(&buf.text).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_addr_utf16() {
+        let text = Rope::from_str("first\nhi 👋 there\n");
+        // the emoji takes two UTF-16 code units
+        assert_eq!(Addr::Utf16(1, 3).as_index_in(&text).unwrap(), 9);
+        assert_eq!(Addr::Utf16(1, 5).as_index_in(&text).unwrap(), 10);
+        assert_eq!(Addr::Utf16(1, 6).as_index_in(&text).unwrap(), 11);
+        assert!(matches!(
+            Addr::Utf16(1, 4).as_index_in(&text),
+            Err(TextError::InvalidAddr(_))
+        ));
+        assert!(Addr::Utf16(1, 20).as_index_in(&text).is_err());
+        assert!(Addr::Utf16(5, 0).as_index_in(&text).is_err());
+
+        assert_eq!(Addr::Index(11).as_utf16_in(&text).unwrap(), (1, 6));
+        assert_eq!(Addr::Index(3).as_utf16_in(&text).unwrap(), (0, 3));
+    }
+
+    #[test]
+    fn test_addr_cmp_in() {
+        let text = Rope::from_str("A minimal\ntext editor.\n");
+        let cmp = |a: Addr, b: Addr| a.cmp_in(&b, &text).unwrap();
+        assert_eq!(cmp(Addr::Index(10), Addr::LineStart(1)), Ordering::Equal);
+        assert_eq!(cmp(Addr::Index(3), Addr::LineStart(1)), Ordering::Less);
+        assert_eq!(cmp(Addr::BufferEnd, Addr::LineStart(1)), Ordering::Greater);
+        assert_eq!(cmp(Addr::LineStart(2), Addr::BufferEnd), Ordering::Equal);
+        assert!(Addr::Index(30).cmp_in(&Addr::BufferEnd, &text).is_err());
+    }
+
+    #[test]
+    fn test_addr_mark() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mark = buf.add_mark(Addr::LineStart(1)).unwrap();
+        buf.insert(Addr::BufferStart, "Well. ").unwrap();
+        assert_eq!(Addr::Mark(mark.clone()).as_index(&buf.text).unwrap(), 20);
+        assert_eq!(
+            Addr::Mark(mark.clone()).as_coordinates(&buf.text).unwrap(),
+            (1, 0)
+        );
+
+        // from the mark to a cursor
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Mark(mark), Addr::Index(27)).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "How are");
+    }
+
+    #[test]
+    fn test_buffer_marks() {
+        let mut buf = Buffer::from_reader("A minimal text editor.".as_bytes()).unwrap();
+        let before = buf.add_mark(Addr::Index(2)).unwrap();
+        let at = buf.add_mark(Addr::Index(10)).unwrap();
+        let after = buf.add_mark(Addr::Index(15)).unwrap();
+        buf.insert(Addr::Index(10), "small ").unwrap();
+        assert_eq!(buf.resolve(&before), 2);
+        assert_eq!(buf.resolve(&at), 16);
+        assert_eq!(buf.resolve(&after), 21);
+
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(10), Addr::Index(16)).unwrap();
+        buf.delete(&mut dot).unwrap();
+        assert_eq!(buf.resolve(&at), 10);
+        assert_eq!(buf.resolve(&after), 15);
+
+        // inside a deletion, a mark collapses onto its start
+        let inside = buf.add_mark(Addr::Index(12)).unwrap();
+        dot.left_right(Addr::Index(10), Addr::Index(15)).unwrap();
+        buf.delete(&mut dot).unwrap();
+        assert_eq!(buf.resolve(&inside), 10);
+        assert_eq!(buf.resolve(&after), 10);
+
+        buf.undo().unwrap();
+        assert_eq!(buf.resolve(&after), 15);
+
+        buf.remove_mark(&after);
+        buf.insert(Addr::BufferStart, "> ").unwrap();
+        assert_eq!(buf.resolve(&before), 4);
+        assert_eq!(buf.resolve(&after), 15);
+        assert!(buf.add_mark(Addr::Index(100)).is_err());
+    }
+
+    #[test]
+    fn test_buffer_lines() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let lines: Vec<String> = buf.lines().iter().map(|l| l.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "Hello there !\n",
+                "How are you ?\n",
+                "I test a text editor.\n",
+                ""
+            ]
+        );
+
+        assert_eq!(buf.line(1).unwrap().slice(), "How are you ?\n");
+        assert_eq!(buf.line(3).unwrap().slice(), "");
+        assert!(buf.line(4).is_err());
+    }
+
+    #[test]
+    fn test_buffer_line_of() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        assert_eq!(buf.line_of(18).unwrap(), 1);
+        assert_eq!(buf.offset_of_line(1).unwrap(), 14);
+        assert_eq!(buf.line_of(buf.offset_of_line(2).unwrap()).unwrap(), 2);
+        assert_eq!(buf.line_of(buf.len_chars()).unwrap(), 3);
+        assert!(buf.line_of(100).is_err());
+        assert!(buf.offset_of_line(5).is_err());
+    }
+
+    #[test]
+    fn test_buffer_viewport() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        assert_eq!(buf.viewport(1, 1).unwrap(), "How are you ?\n");
+        assert_eq!(
+            buf.viewport(0, 2).unwrap(),
+            "Hello there !\nHow are you ?\n"
+        );
+        assert_eq!(buf.viewport(2, 10).unwrap(), "I test a text editor.\n");
+        assert_eq!(buf.viewport(1, 0).unwrap(), "");
+        assert!(buf.viewport(5, 1).is_err());
+        assert_eq!(
+            buf.viewport(2, usize::MAX).unwrap(),
+            "I test a text editor.\n"
+        );
+    }
+
+    #[test]
+    fn test_buffer_on_change() {
+        let mut buf = Buffer::from_reader("A minimal text editor.".as_bytes()).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&events);
+        buf.on_change(move |e| seen.lock().unwrap().push(e.clone()));
+        let count = Arc::new(Mutex::new(0));
+        let counted = Arc::clone(&count);
+        buf.on_change(move |_| *counted.lock().unwrap() += 1);
+
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(2), Addr::Index(9)).unwrap();
+        buf.set(&mut dot, RopeSlice::from("tiny")).unwrap();
+        buf.insert(Addr::BufferEnd, "\n").unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                ChangeEvent {
+                    at: 2,
+                    removed: 7,
+                    inserted: "tiny".to_string()
+                },
+                ChangeEvent {
+                    at: 19,
+                    removed: 0,
+                    inserted: "\n".to_string()
+                },
+            ]
+        );
+
+        buf.undo().unwrap();
+        assert_eq!(events.lock().unwrap()[2].removed, 1);
+        assert_eq!(*count.lock().unwrap(), 3);
+
+        // shared handles notify the same subscribers, clones don't
+        buf.share().insert(Addr::BufferStart, "> ").unwrap();
+        assert_eq!(*count.lock().unwrap(), 4);
+        buf.clone().insert(Addr::BufferStart, "> ").unwrap();
+        assert_eq!(*count.lock().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_buffer_snapshot() {
+        let mut buf = Buffer::from_reader("A minimal text editor.".as_bytes()).unwrap();
+        let snapshot = buf.snapshot();
+        buf.insert(Addr::BufferStart, "> ").unwrap();
+        assert_eq!(buf.to_string(), "> A minimal text editor.");
+        assert_eq!(snapshot.to_string(), "A minimal text editor.");
+        assert!(!snapshot.is_dirty());
+    }
+
+    #[test]
+    fn test_buffer_clone() {
+        let buf = Buffer::from_reader("A minimal text editor.".as_bytes()).unwrap();
+        let mut clone = buf.clone();
+        clone.insert(Addr::BufferStart, "> ").unwrap();
+        assert_eq!(buf.to_string(), "A minimal text editor.");
+        assert_eq!(clone.to_string(), "> A minimal text editor.");
+        clone.undo().unwrap();
+        assert_eq!(clone.to_string(), "A minimal text editor.");
+
+        let mut shared = buf.share();
+        shared.insert(Addr::BufferEnd, "\n").unwrap();
+        assert_eq!(buf.to_string(), "A minimal text editor.\n");
+    }
+
+    #[test]
+    fn test_buffer_foreign_dot() {
+        let mut a = Buffer::from("aaaa");
+        let b = Buffer::from("bbbb");
+        let mut dot = Dot::from_indices(&b, 1, 3);
+        assert!(matches!(
+            a.set_str(&mut dot, "Z"),
+            Err(TextError::InvalidAddr(_))
+        ));
+        assert!(a.delete(&mut dot).is_err());
+        assert!(a.uppercase(&mut dot).is_err());
+        assert!(a.indent(&mut dot, "  ").is_err());
+        assert!(a.sort_lines(&dot, false).is_err());
+        assert!(a.find_next("a", &dot, true).is_err());
+        let mut other = Dot::from_indices(&a, 0, 1);
+        assert!(a.transpose(&mut other, &mut dot).is_err());
+        assert!(a.yank_named(&dot, 'a').is_err());
+        assert!(a.register('a').is_none());
+        assert_eq!(a.to_string(), "aaaa");
+        assert_eq!(b.to_string(), "bbbb");
+
+        let mut selections = Selections::new();
+        selections.add(Dot::from_indices(&a, 0, 1)).unwrap();
+        assert!(selections.add(dot).is_err());
+        assert_eq!(selections.len(), 1);
+    }
+
+    #[test]
+    fn test_buffer_share_history() {
+        let mut a = Buffer::from("abc");
+        a.insert(Addr::Index(3), "X").unwrap();
+        let mut b = a.share();
+        b.insert(Addr::BufferStart, "YY").unwrap();
+        // undoes the latest edit, whichever handle made it
+        a.undo().unwrap().unwrap();
+        assert_eq!(a.to_string(), "abcX");
+        a.undo().unwrap().unwrap();
+        assert_eq!(b.to_string(), "abc");
+        b.redo().unwrap().unwrap();
+        b.redo().unwrap().unwrap();
+        assert_eq!(a.to_string(), "YYabcX");
+    }
+
+    #[test]
+    fn test_buffer_insert_newline() {
+        let mut buf = Buffer::from("fn main() {\n    let a = 1;\n}\n");
+        let dot = buf
+            .insert_newline(Addr::LineEndBeforeNewline(1), true)
+            .unwrap();
+        assert_eq!(buf.to_string(), "fn main() {\n    let a = 1;\n    \n}\n");
+        assert_eq!(dot.indices().unwrap(), (31, 31));
+        assert_eq!(Addr::Index(31).as_coordinates(&buf.text).unwrap(), (2, 4));
+
+        let mut buf = Buffer::from("\t\tdeep\n");
+        let dot = buf.insert_newline(Addr::Index(6), true).unwrap();
+        assert_eq!(buf.to_string(), "\t\tdeep\n\t\t\n");
+        assert_eq!(dot.indices().unwrap(), (9, 9));
+
+        let mut buf = Buffer::from("\tsplit here\r\n");
+        let dot = buf.insert_newline(Addr::Index(6), false).unwrap();
+        assert_eq!(buf.to_string(), "\tsplit\r\n here\r\n");
+        assert_eq!(dot.indices().unwrap(), (8, 8));
+    }
+
+    #[test]
+    fn test_buffer_indent() {
+        let mut buf = Buffer::from("one\ntwo\n\nthree\n");
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(1), Addr::Index(5)).unwrap();
+        buf.indent(&mut dot, "    ").unwrap();
+        assert_eq!(buf.to_string(), "    one\n    two\n\nthree\n");
+        assert_eq!(buf.get(&dot).unwrap(), "    one\n    two\n");
+
+        // blank lines are left alone
+        dot.left_right(Addr::LineStart(1), Addr::LineEnd(3))
+            .unwrap();
+        buf.indent(&mut dot, "\t").unwrap();
+        assert_eq!(buf.to_string(), "    one\n\t    two\n\n\tthree\n");
+        buf.undo().unwrap();
+        assert_eq!(buf.to_string(), "    one\n    two\n\nthree\n");
+    }
+
+    #[test]
+    fn test_buffer_toggle_comment() {
+        let mut buf = Buffer::from("fn main() {\n    let a = 1;\n\n    let b = 2;\n}\n");
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::LineStart(1), Addr::LineEnd(3))
+            .unwrap();
+        buf.toggle_comment(&mut dot, "// ").unwrap();
+        assert_eq!(
+            buf.to_string(),
+            "fn main() {\n    // let a = 1;\n\n    // let b = 2;\n}\n"
+        );
+        buf.toggle_comment(&mut dot, "// ").unwrap();
+        assert_eq!(
+            buf.to_string(),
+            "fn main() {\n    let a = 1;\n\n    let b = 2;\n}\n"
+        );
+
+        // a partly commented block gets commented
+        let mut buf = Buffer::from("//a\nb\n");
+        let mut dot = Dot::new(&buf);
+        buf.toggle_comment(&mut dot, "// ").unwrap();
+        assert_eq!(buf.to_string(), "// //a\n// b\n");
+        buf.uncomment_lines(&mut dot, "// ").unwrap();
+        buf.uncomment_lines(&mut dot, "// ").unwrap();
+        assert_eq!(buf.to_string(), "a\nb\n");
+    }
+
+    #[test]
+    fn test_buffer_dedent() {
+        let mut buf = Buffer::from("    one\n  two\n\tthree\nfour\n");
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(5), Addr::LineStart(3)).unwrap();
+        buf.dedent(&mut dot, "    ").unwrap();
+        assert_eq!(buf.to_string(), "one\ntwo\nthree\nfour\n");
+        assert_eq!(buf.get(&dot).unwrap(), "one\ntwo\nthree\n");
+
+        let mut dot = Dot::new(&buf);
+        buf.dedent(&mut dot, "    ").unwrap();
+        assert_eq!(buf.to_string(), "one\ntwo\nthree\nfour\n");
+        // nothing left to dedent, so nothing was recorded
+        buf.undo().unwrap();
+        assert_eq!(buf.to_string(), "    one\n  two\n\tthree\nfour\n");
+    }
+
+    #[test]
+    fn test_buffer_count_matches() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        assert_eq!(buf.count_matches(r"\w+").unwrap(), 10);
+        assert_eq!(buf.count_matches("xyz").unwrap(), 0);
+        assert_eq!(buf.count_matches("(?m)^").unwrap(), 4);
+        assert!(matches!(
+            buf.count_matches("(unclosed"),
+            Err(TextError::Regex(_))
+        ));
+    }
+
+    #[test]
+    fn test_buffer_search_options() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let nocase = SearchOptions {
+            case_insensitive: true,
+            ..Default::default()
+        };
+        assert_eq!(buf.count_matches("h").unwrap(), 1);
+        assert_eq!(buf.count_matches_with_options("h", nocase).unwrap(), 3);
+        let dots = buf.find_all_with_options("HELLO|i ", nocase).unwrap();
+        let found: Vec<_> = dots.iter().map(|d| buf.get(d).unwrap()).collect();
+        assert_eq!(found, ["Hello", "I "]);
+
+        let multi = SearchOptions {
+            multi_line: true,
+            ..Default::default()
+        };
+        assert_eq!(buf.count_matches(r"^\w+").unwrap(), 1);
+        let dots = buf.find_all_with_options(r"^\w+|\S$", multi).unwrap();
+        let found: Vec<_> = dots.iter().map(|d| buf.get(d).unwrap()).collect();
+        assert_eq!(found, ["Hello", "!", "How", "?", "I", "."]);
+
+        let dotall = SearchOptions {
+            dot_matches_newline: true,
+            ..Default::default()
+        };
+        assert_eq!(buf.count_matches("!.H").unwrap(), 0);
+        assert_eq!(buf.count_matches_with_options("!.H", dotall).unwrap(), 1);
+
+        let n = buf.replace_all_with_options("^", "> ", multi).unwrap();
+        assert_eq!(n, 4);
+        assert_eq!(
+            buf.to_string(),
+            "> Hello there !\n> How are you ?\n> I test a text editor.\n> "
+        );
+    }
+
+    #[test]
+    fn test_buffer_regex_cache() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let compiled = || buf.regexes.lock().unwrap().compiled;
+        let mut dot = Dot::from_indices(&buf, 0, 0);
+        dot = buf.find_next("e", &dot, false).unwrap().unwrap();
+        dot = buf.find_next("e", &dot, false).unwrap().unwrap();
+        assert_eq!(dot.indices().unwrap(), (8, 9));
+        assert_eq!(compiled(), 1);
+        assert_eq!(buf.count_matches("e").unwrap(), 7);
+        assert_eq!(compiled(), 1);
+
+        // other flags are another entry
+        let nocase = SearchOptions {
+            case_insensitive: true,
+            ..Default::default()
+        };
+        buf.count_matches_with_options("e", nocase).unwrap();
+        assert_eq!(compiled(), 2);
+
+        let matcher = buf.matcher("e").unwrap();
+        assert_eq!(compiled(), 2);
+        let dot = matcher.find_next(&buf, &dot, false).unwrap().unwrap();
+        assert_eq!(dot.indices().unwrap(), (10, 11));
+        assert_eq!(matcher.count_matches(&buf), 7);
+        assert_eq!(matcher.find_all(&buf).len(), 7);
+        let dot = matcher.find_prev(&buf, &dot, false).unwrap().unwrap();
+        assert_eq!(dot.indices().unwrap(), (8, 9));
+
+        // the oldest pattern is dropped once the cache is full
+        for i in 0..REGEX_CACHE_LEN {
+            buf.count_matches(&i.to_string()).unwrap();
+        }
+        assert_eq!(compiled(), 2 + REGEX_CACHE_LEN);
+        buf.count_matches("e").unwrap();
+        assert_eq!(compiled(), 3 + REGEX_CACHE_LEN);
+        assert!(matches!(buf.matcher("(unclosed"), Err(TextError::Regex(_))));
+
+        // dots search through their buffer's cache
+        let buf = Buffer::from("one two one two");
+        let compiled = || buf.regexes.lock().unwrap().compiled;
+        let mut dot = Dot::from_indices(&buf, 0, 0);
+        dot.move_to_next_match("two", true).unwrap();
+        dot.move_to_next_match("two", true).unwrap();
+        assert_eq!(dot.indices().unwrap(), (12, 15));
+        Dot::new(&buf).split_matches("two").unwrap();
+        parse_address(&buf, "/two/").unwrap();
+        assert_eq!(compiled(), 1);
+    }
+
+    #[test]
+    fn test_buffer_find_all() {
+        let buf = Buffer::from("a1bb22");
+        let dots = buf.find_all(r"\d+").unwrap();
+        let spans: Vec<_> = dots.iter().map(|d| d.indices().unwrap()).collect();
+        assert_eq!(spans, [(1, 2), (4, 6)]);
+        assert_eq!(buf.get(&dots[1]).unwrap(), "22");
+
+        let spans: Vec<_> = buf
+            .find_all("x*")
+            .unwrap()
+            .iter()
+            .map(|d| d.indices().unwrap())
+            .collect();
+        assert_eq!(spans.len(), 7);
+        assert_eq!(spans[6], (6, 6));
+        assert!(buf.find_all("[").is_err());
+    }
+
+    #[test]
+    fn test_buffer_find_next_prev() {
+        let buf = Buffer::from("a1bb22c333");
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::BufferStart, Addr::BufferStart)
+            .unwrap();
+        let mut spans = Vec::new();
+        while let Some(next) = buf.find_next(r"\d+", &dot, false).unwrap() {
+            spans.push(next.indices().unwrap());
+            dot = next;
+        }
+        assert_eq!(spans, [(1, 2), (4, 6), (7, 10)]);
+        let wrapped = buf.find_next(r"\d+", &dot, true).unwrap().unwrap();
+        assert_eq!(wrapped.indices().unwrap(), (1, 2));
+
+        let prev = buf.find_prev(r"\d+", &dot, false).unwrap().unwrap();
+        assert_eq!(buf.get(&prev).unwrap(), "22");
+        assert!(buf.find_prev(r"\d+", &wrapped, false).unwrap().is_none());
+        let wrapped = buf.find_prev(r"\d+", &wrapped, true).unwrap().unwrap();
+        assert_eq!(buf.get(&wrapped).unwrap(), "333");
+
+        // empty matches still make progress
+        dot.left_right(Addr::Index(2), Addr::Index(2)).unwrap();
+        let next = buf.find_next("x*", &dot, false).unwrap().unwrap();
+        assert_eq!(next.indices().unwrap(), (3, 3));
+        assert!(buf.find_next("z", &dot, true).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_buffer_visual_column() {
+        let buf = Buffer::from("a\tb  \tc\n\tx\n");
+        let columns: Vec<_> = (0..7)
+            .map(|i| buf.visual_column(Addr::Index(i), 4).unwrap())
+            .collect();
+        assert_eq!(columns, [0, 1, 4, 5, 6, 7, 8]);
+        assert_eq!(buf.visual_column(Addr::Coordinates(1, 1), 4).unwrap(), 4);
+
+        let at = |line, column| {
+            let addr = buf.addr_at_visual_column(line, column, 4).unwrap();
+            addr.as_index(&buf.text).unwrap()
+        };
+        assert_eq!(at(0, 0), 0);
+        assert_eq!(at(0, 2), 1);
+        assert_eq!(at(0, 4), 2);
+        assert_eq!(at(0, 7), 5);
+        assert_eq!(at(0, 8), 6);
+        assert_eq!(at(0, 30), 7);
+        assert_eq!(at(1, 3), 8);
+        assert!(buf.addr_at_visual_column(5, 0, 4).is_err());
+    }
+
+    #[test]
+    fn test_buffer_registers() {
+        let mut buf = Buffer::from("A minimal text editor.");
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(2), Addr::Index(10)).unwrap();
+        let mut reg = Register::new();
+        dot.yank(&buf, &mut reg).unwrap();
+        assert_eq!(reg.text(), "minimal ");
+
+        let pasted = buf.paste(Addr::Index(10), &reg).unwrap();
+        assert_eq!(buf.to_string(), "A minimal minimal text editor.");
+        assert_eq!(pasted.indices().unwrap(), (10, 18));
+
+        dot.left_right(Addr::Index(18), Addr::Index(22)).unwrap();
+        buf.yank_named(&dot, 'a').unwrap();
+        assert_eq!(buf.register('a').unwrap().text(), "text");
+        assert!(buf.register('b').is_none());
+        let pasted = buf.paste_named(Addr::BufferStart, 'a').unwrap();
+        assert_eq!(buf.get(&pasted).unwrap(), "text");
+        assert!(
+            buf.paste_named(Addr::BufferStart, 'b')
+                .unwrap()
+                .is_empty()
+                .unwrap()
+        );
+        assert_eq!(buf.to_string(), "textA minimal minimal text editor.");
+    }
+
+    #[test]
+    fn test_buffer_transpose() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut hello = Dot::new(&buf);
+        hello.left_right(Addr::Index(0), Addr::Index(5)).unwrap();
+        let mut there = Dot::new(&buf);
+        there.left_right(Addr::Index(6), Addr::Index(11)).unwrap();
+        buf.transpose(&mut there, &mut hello).unwrap();
+        assert_eq!(buf.viewport(0, 1).unwrap(), "there Hello !\n");
+        assert_eq!(buf.get(&hello).unwrap(), "Hello");
+        assert_eq!(buf.get(&there).unwrap(), "there");
+
+        // spans of different lengths
+        let mut you = Dot::new(&buf);
+        you.left_right(Addr::Index(22), Addr::Index(25)).unwrap();
+        buf.transpose(&mut hello, &mut you).unwrap();
+        assert_eq!(
+            buf.viewport(0, 2).unwrap(),
+            "there you !\nHow are Hello ?\n"
+        );
+        assert_eq!(buf.get(&hello).unwrap(), "Hello");
+        assert_eq!(buf.get(&you).unwrap(), "you");
+
+        buf.undo().unwrap();
+        assert_eq!(
+            buf.viewport(0, 2).unwrap(),
+            "there Hello !\nHow are you ?\n"
+        );
+        hello.left_right(Addr::Index(2), Addr::Index(8)).unwrap();
+        assert!(buf.transpose(&mut hello, &mut there).is_err());
+    }
+
+    #[test]
+    fn test_buffer_case() {
+        let mut buf = Buffer::from("die straße ist ÉTROITE");
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(4), Addr::Index(10)).unwrap();
+        buf.uppercase(&mut dot).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "STRASSE");
+        assert_eq!(buf.to_string(), "die STRASSE ist ÉTROITE");
+
+        let mut all = Dot::new(&buf);
+        buf.titlecase(&mut all).unwrap();
+        assert_eq!(buf.to_string(), "Die Strasse Ist Étroite");
+        buf.lowercase(&mut all).unwrap();
+        assert_eq!(buf.get(&all).unwrap(), "die strasse ist étroite");
+
+        buf.undo().unwrap();
+        buf.undo().unwrap();
+        assert_eq!(buf.to_string(), "die STRASSE ist ÉTROITE");
+    }
+
+    #[test]
+    fn test_buffer_delete_duplicate_line() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let dot = buf.delete_line(1).unwrap();
+        assert_eq!(buf.to_string(), "Hello there !\nI test a text editor.\n");
+        assert_eq!(
+            buf.get(&Dot::from_indices(&buf, 0, 14)).unwrap(),
+            "Hello there !\n"
+        );
+        assert_eq!(dot.indices_in(&buf.text.read().unwrap()).unwrap(), (14, 14));
+
+        let dot = buf.duplicate_line(0).unwrap();
+        assert_eq!(
+            buf.to_string(),
+            "Hello there !\nHello there !\nI test a text editor.\n"
+        );
+        assert_eq!(dot.indices_in(&buf.text.read().unwrap()).unwrap(), (14, 14));
+        assert!(buf.duplicate_line(3).is_err());
+
+        let mut buf = Buffer::from("one\ntwo");
+        let dot = buf.duplicate_line(1).unwrap();
+        assert_eq!(buf.to_string(), "one\ntwo\ntwo");
+        assert_eq!(dot.indices_in(&buf.text.read().unwrap()).unwrap(), (8, 8));
+        buf.delete_line(9).unwrap();
+        let dot = buf.delete_line(9).unwrap();
+        assert_eq!(buf.to_string(), "one");
+        assert_eq!(dot.indices_in(&buf.text.read().unwrap()).unwrap(), (0, 0));
+        buf.delete_line(0).unwrap();
+        buf.delete_line(0).unwrap();
+        assert_eq!(buf.to_string(), "");
+    }
+
+    #[test]
+    fn test_buffer_join_lines() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let dot = buf.join_lines(1).unwrap();
+        assert_eq!(
+            buf.to_string(),
+            "Hello there !\nHow are you ? I test a text editor.\n"
+        );
+        assert_eq!(dot.indices_in(&buf.text.read().unwrap()).unwrap(), (27, 27));
+        assert!(buf.join_lines(1).is_err());
+        buf.undo().unwrap();
+        assert!(buf == Buffer::from_file(Path::new("tests/test.txt")).unwrap());
+
+        let mut buf = Buffer::from("fn main() {\r\n    body\n\t\n}");
+        buf.join_lines(0).unwrap();
+        assert_eq!(buf.to_string(), "fn main() { body\n\t\n}");
+        buf.join_lines(0).unwrap();
+        assert_eq!(buf.to_string(), "fn main() { body\n}");
+    }
+
+    #[test]
+    fn test_buffer_ensure_trailing_newline() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        assert!(!buf.ensure_trailing_newline().unwrap());
+        assert_eq!(buf.len_chars(), 50);
+
+        let mut buf = Buffer::from("A minimal\ntext editor.");
+        assert!(buf.ensure_trailing_newline().unwrap());
+        assert_eq!(buf.to_string(), "A minimal\ntext editor.\n");
+        assert!(!buf.ensure_trailing_newline().unwrap());
+        buf.undo().unwrap();
+        assert_eq!(buf.to_string(), "A minimal\ntext editor.");
+
+        let mut buf = Buffer::from("a\r\nb");
+        assert!(buf.ensure_trailing_newline().unwrap());
+        assert_eq!(buf.to_string(), "a\r\nb\r\n");
+        assert!(!Buffer::from("").ensure_trailing_newline().unwrap());
+
+        let mut buf = Buffer::from("a");
+        buf.set_read_only(true);
+        assert!(matches!(
+            buf.ensure_trailing_newline(),
+            Err(TextError::ReadOnly)
+        ));
+    }
+
+    #[test]
+    fn test_buffer_trim_trailing_whitespace() {
+        let mut buf = Buffer::from("one  \ntwo\t \r\n\n \nthree\nfour \n");
+        assert_eq!(buf.trim_trailing_whitespace().unwrap(), 6);
+        assert_eq!(buf.to_string(), "one\ntwo\r\n\n\nthree\nfour\n");
+        assert_eq!(buf.trim_trailing_whitespace().unwrap(), 0);
+        buf.undo().unwrap();
+        assert_eq!(buf.to_string(), "one  \ntwo\t \r\n\n \nthree\nfour \n");
+    }
+
+    #[test]
+    fn test_parse_address() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let get = |expr| buf.get(&parse_address(&buf, expr).unwrap()).unwrap();
+        assert_eq!(get("2"), "How are you ?\n");
+        assert_eq!(get("0"), "");
+        assert_eq!(get("$"), "");
+        assert_eq!(get("/are/"), "are");
+        assert_eq!(get("/e/"), "e");
+        assert_eq!(get("?e?"), "e");
+        assert_eq!(get(".+2"), "How are you ?\n");
+        assert_eq!(get("1+"), "How are you ?\n");
+        assert_eq!(get("$-1"), "I test a text editor.\n");
+        assert_eq!(get("3-2"), "Hello there !\n");
+        assert_eq!(get("2+/t/"), "t");
+        assert_eq!(get("1,2"), "Hello there !\nHow are you ?\n");
+        assert_eq!(get("2,"), "How are you ?\nI test a text editor.\n");
+        assert_eq!(get("0,$"), buf.to_string());
+        assert_eq!(get(","), buf.to_string());
+        assert_eq!(get("/How/,/you/"), "How are you");
+        assert_eq!(get("1,.+1"), "Hello there !\n");
+
+        let dot = parse_address(&buf, "?editor?").unwrap();
+        assert_eq!(dot.indices_in(&buf.text.read().unwrap()).unwrap(), (42, 48));
+        let dot = parse_address(&buf, "/x?t/").unwrap();
+        assert_eq!(dot.indices_in(&buf.text.read().unwrap()).unwrap(), (6, 7));
+
+        for expr in ["", "4", "3,1", "1;2", "1-2", "/(/"] {
+            assert!(parse_address(&buf, expr).is_err(), "{expr}");
+        }
+        let ret = parse_address(&buf, "/xyz/");
+        assert!(matches!(ret, Err(TextError::NoMatch(_))));
+    }
+
+    #[test]
+    fn test_dot_move_to_next_match() {
+        let buf = Buffer::from("the cat and the dog and the end");
+        let mut dot = Dot::new(&buf);
+        let at = |dot: &Dot| dot.indices_in(&buf.text.read().unwrap()).unwrap();
+        dot.left_right(Addr::BufferStart, Addr::BufferStart)
+            .unwrap();
+        dot.move_to_next_match("the", false).unwrap();
+        assert_eq!(at(&dot), (0, 3));
+        dot.move_to_next_match("the", false).unwrap();
+        assert_eq!(at(&dot), (12, 15));
+        dot.move_to_next_match("and", false).unwrap();
+        assert_eq!(at(&dot), (20, 23));
+        dot.move_to_next_match("the", false).unwrap();
+
+        let ret = dot.move_to_next_match("the", false);
+        assert!(matches!(ret, Err(TextError::NoMatch(_))));
+        assert_eq!(at(&dot), (24, 27));
+        dot.move_to_next_match("and", true).unwrap();
+        assert_eq!(at(&dot), (8, 11));
+        let ret = dot.move_to_next_match("xyz", true);
+        assert!(matches!(ret, Err(TextError::NoMatch(_))));
+    }
+
+    #[test]
+    fn test_buffer_set_from_reader() {
+        let mut buf = Buffer::from("A minimal text editor.");
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(2), Addr::Index(9)).unwrap();
+        let reader = io::Cursor::new("très petit".as_bytes().to_vec());
+        buf.set_from_reader(&mut dot, reader).unwrap();
+        assert_eq!(buf.to_string(), "A très petit text editor.");
+        assert_eq!(buf.get(&dot).unwrap(), "très petit");
+
+        // a char split between two reads
+        let bytes = "né".as_bytes();
+        let reader = io::Read::chain(&bytes[..2], &bytes[2..]);
+        buf.set_from_reader(&mut dot, reader).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "né");
+
+        assert!(buf.set_from_reader(&mut dot, &b"ok\xff"[..]).is_err());
+        assert!(buf.set_from_reader(&mut dot, &bytes[..2]).is_err());
+        assert_eq!(buf.to_string(), "A né text editor.");
+        buf.undo().unwrap();
+        assert_eq!(buf.to_string(), "A très petit text editor.");
+
+        // the reader can read the buffer it is filling
+        struct Echo(Buffer);
+        impl io::Read for Echo {
+            fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+                let text = self.0.to_string();
+                let n = text.len().min(out.len());
+                out[..n].copy_from_slice(&text.as_bytes()[..n]);
+                self.0 = Buffer::new();
+                Ok(n)
+            }
+        }
+        let mut dot = Dot::from_indices(&buf, 0, 0);
+        buf.set_from_reader(&mut dot, Echo(buf.share())).unwrap();
+        assert_eq!(buf.to_string(), "A très petit text editor.".repeat(2));
+
+        buf.set_read_only(true);
+        assert!(matches!(
+            buf.set_from_reader(&mut dot, &b"ok"[..]),
+            Err(TextError::ReadOnly)
+        ));
+    }
+
+    #[test]
+    fn test_buffer_append_prepend() {
+        let mut buf = Buffer::from_reader("text".as_bytes()).unwrap();
+        let dot = buf.append(" editor.").unwrap();
+        assert_eq!(dot.indices().unwrap(), (4, 12));
+        assert_eq!(buf.get(&dot).unwrap(), " editor.");
+        let dot = buf.prepend("A minimal ").unwrap();
+        assert_eq!(dot.indices().unwrap(), (0, 10));
+        assert_eq!(buf.to_string(), "A minimal text editor.");
+    }
+
+    #[test]
+    fn test_buffer_from_file() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::BufferStart, Addr::BufferEnd).unwrap();
+        assert_eq!(
+            buf.get(&dot).unwrap(),
+            String::from("Hello there !\nHow are you ?\nI test a text editor.\n")
+        );
+    }
+
+    #[test]
+    fn test_buffer_io_error() {
+        let ret = Buffer::from_file(Path::new("tests/missing.txt"));
+        assert!(matches!(ret, Err(TextError::Io(_))));
+    }
+
+    #[test]
+    fn test_buffer_invalid_utf8() {
+        let ret = Buffer::from_reader(&b"Hello \xff there"[..]);
+        assert!(matches!(
+            ret,
+            Err(TextError::InvalidUtf8 {
+                byte_offset: Some(6)
+            })
+        ));
+        // a truncated char at the very end
+        let ret = Buffer::from_reader("Noël".as_bytes()[..3].as_ref());
+        assert!(matches!(
+            ret,
+            Err(TextError::InvalidUtf8 {
+                byte_offset: Some(2)
+            })
+        ));
+        let ret = Buffer::from_bytes(b"\xef\xbb\xbfok\xc0", Encoding::Detect);
+        assert!(matches!(
+            ret,
+            Err(TextError::InvalidUtf8 {
+                byte_offset: Some(5)
+            })
+        ));
+    }
+
+    #[test]
+    fn test_buffer_save() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let path = std::env::temp_dir().join(format!("big-save-{}.txt", std::process::id()));
+        buf.save(&path).unwrap();
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            std::fs::read("tests/test.txt").unwrap()
+        );
+        let saved = Buffer::from_file(&path).unwrap();
+        assert_eq!(
+            saved.get(&Dot::new(&saved)).unwrap(),
+            buf.get(&Dot::new(&buf)).unwrap()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_buffer_save_atomic() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let path = std::env::temp_dir().join(format!("big-atomic-{}.txt", std::process::id()));
+        buf.save_atomic(&path).unwrap();
+        buf.insert(Addr::BufferStart, "> ").unwrap();
+        buf.save_atomic(&path).unwrap();
+        let saved = Buffer::from_file(&path).unwrap();
+        assert_eq!(
+            saved.get(&Dot::new(&saved)).unwrap(),
+            "> Hello there !\nHow are you ?\nI test a text editor.\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_buffer_crlf() {
+        let mut buf = Buffer::from_reader("one\r\ntwo\nthree\r\n".as_bytes()).unwrap();
+        assert_eq!(buf.line_ending(), LineEnding::Crlf);
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::LineStart(0), Addr::LineEndBeforeNewline(0))
+            .unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "one");
+        dot.left_right(Addr::LineStart(0), Addr::LineEnd(0))
+            .unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "one\r\n");
+        assert_eq!(
+            Addr::LineEndBeforeNewline(1)
+                .as_coordinates(&buf.text)
+                .unwrap(),
+            (1, 3)
+        );
+
+        let path = std::env::temp_dir().join(format!("big-crlf-{}.txt", std::process::id()));
+        buf.save(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"one\r\ntwo\r\nthree\r\n");
+        buf.set_line_ending(LineEnding::Lf);
+        buf.save_atomic(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"one\ntwo\nthree\n");
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(Buffer::from("one\ntwo\r\n").line_ending(), LineEnding::Lf);
+        assert_eq!(Buffer::new().line_ending(), LineEnding::Lf);
+    }
+
+    #[test]
+    fn test_buffer_from_bytes() {
+        let mut bytes = vec![0xff, 0xfe];
+        bytes.extend("né 👋\n".encode_utf16().flat_map(u16::to_le_bytes));
+        let mut buf = Buffer::from_bytes(&bytes, Encoding::Detect).unwrap();
+        assert_eq!(buf.encoding(), Encoding::Utf16Le);
+        assert_eq!(buf.to_string(), "né 👋\n");
+
+        let path = std::env::temp_dir().join(format!("big-utf16-{}.txt", std::process::id()));
+        buf.save(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut buf = Buffer::from_bytes(b"caf\xe9 cr\xe8me\n", Encoding::Latin1).unwrap();
+        assert_eq!(buf.to_string(), "café crème\n");
+        let path = std::env::temp_dir().join(format!("big-latin1-{}.txt", std::process::id()));
+        buf.save(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"caf\xe9 cr\xe8me\n");
+        buf.insert(Addr::BufferStart, "👋").unwrap();
+        assert!(buf.save(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let mut buf = Buffer::from_bytes(b"\xef\xbb\xbfbom\n", Encoding::Detect).unwrap();
+        assert_eq!(buf.encoding(), Encoding::Utf8);
+        assert_eq!(buf.to_string(), "bom\n");
+        let path = std::env::temp_dir().join(format!("big-bom-{}.txt", std::process::id()));
+        buf.save(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"\xef\xbb\xbfbom\n");
+        std::fs::remove_file(&path).unwrap();
+
+        let buf = Buffer::from_bytes("plain".as_bytes(), Encoding::Detect).unwrap();
+        assert_eq!(buf.encoding(), Encoding::Utf8);
+        assert!(Buffer::from_bytes(b"\xff\xff\xff", Encoding::Utf8).is_err());
+    }
+
+    #[test]
+    fn test_buffer_dirty() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        assert!(!buf.is_dirty());
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::BufferStart, Addr::Index(5)).unwrap();
+        buf.set(&mut dot, RopeSlice::from("Howdy")).unwrap();
+        assert!(buf.is_dirty());
+        assert!(buf.clone().is_dirty());
+
+        let path = std::env::temp_dir().join(format!("big-dirty-{}.txt", std::process::id()));
+        buf.save(&path).unwrap();
+        assert!(!buf.is_dirty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_buffer_len() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        assert_eq!(buf.len_chars(), 50);
+        assert_eq!(buf.len_bytes(), 50);
+        // the empty line after the final newline counts
+        assert_eq!(buf.len_lines(), 4);
+
+        let buf = Buffer::from_reader("héllo".as_bytes()).unwrap();
+        assert_eq!(buf.len_chars(), 5);
+        assert_eq!(buf.len_bytes(), 6);
+        assert_eq!(buf.len_lines(), 1);
+    }
+
+    #[test]
+    fn test_buffer_end() {
+        // trailing newline
+        let buf = Buffer::from_reader("abc\ndef\n".as_bytes()).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::BufferStart, Addr::BufferEnd).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "abc\ndef\n");
+        assert_eq!(Addr::BufferEnd.as_coordinates(&buf.text).unwrap(), (2, 0));
+
+        // no trailing newline
+        let buf = Buffer::from_reader("abc\ndef".as_bytes()).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::BufferStart, Addr::BufferEnd).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "abc\ndef");
+        assert_eq!(Addr::BufferEnd.as_coordinates(&buf.text).unwrap(), (1, 3));
+    }
+
+    #[test]
+    fn test_empty_buffer() {
+        let buf = Buffer::new();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::BufferStart, Addr::BufferEnd).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "");
+        assert_eq!(Addr::BufferEnd.as_index(&buf.text).unwrap(), 0);
+        assert_eq!(Addr::BufferEnd.as_coordinates(&buf.text).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn test_addr_in_rope() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let text = buf.text.read().unwrap();
+        assert_eq!(Addr::LineStart(1).as_index_in(&text).unwrap(), 14);
+        assert_eq!(Addr::Coordinates(2, 2).as_index_in(&text).unwrap(), 30);
+        assert_eq!(Addr::Index(30).as_coordinates_in(&text).unwrap(), (2, 2));
+        assert_eq!(Addr::BufferEnd.as_coordinates_in(&text).unwrap(), (3, 0));
+    }
+
+    #[test]
+    fn test_addr_coordinates() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let text = buf.text.read().unwrap();
+        assert_eq!(Addr::Coordinates(1, 4).as_index_in(&text).unwrap(), 18);
+        // line end, right after the newline
+        assert_eq!(Addr::Coordinates(0, 14).as_index_in(&text).unwrap(), 14);
+        assert!(Addr::Coordinates(0, 15).as_index_in(&text).is_err());
+        assert!(Addr::Coordinates(5, 0).as_index_in(&text).is_err());
+    }
+
+    #[test]
+    fn test_addr_line_end() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::LineStart(1), Addr::LineEnd(1))
+            .unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "How are you ?\n");
+        dot.left_right(Addr::LineStart(1), Addr::LineEndBeforeNewline(1))
+            .unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "How are you ?");
+        assert_eq!(Addr::LineEnd(1).as_coordinates(&buf.text).unwrap(), (1, 14));
+        assert_eq!(
+            Addr::LineEndBeforeNewline(1)
+                .as_coordinates(&buf.text)
+                .unwrap(),
+            (1, 13)
+        );
+
+        // last line without a newline
+        let buf = Buffer::from_reader("abc\ndef".as_bytes()).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::LineStart(1), Addr::LineEndBeforeNewline(1))
+            .unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "def");
+        assert!(Addr::LineEnd(2).as_index(&buf.text).is_err());
+    }
+
+    #[test]
+    fn test_addr_move() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut addr = Addr::LineStart(1);
+        addr.move_right(&buf.text, 4).unwrap();
+        assert_eq!(addr.as_index(&buf.text).unwrap(), 18);
+        addr.move_left(&buf.text, 18).unwrap();
+        assert_eq!(addr.as_index(&buf.text).unwrap(), 0);
+        assert!(addr.move_left(&buf.text, 1).is_err());
+
+        let mut addr = Addr::BufferEnd;
+        assert!(addr.move_right(&buf.text, 1).is_err());
+        // out of range addresses can't be moved back into the buffer
+        let mut addr = Addr::Index(100);
+        assert!(addr.move_left(&buf.text, 1).is_err());
+    }
+
+    #[test]
+    fn test_dot_anchor_left() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.anchor_left(Addr::BufferStart, Addr::Index(5)).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "Hello");
+        assert!(matches!(
+            dot.anchor_left(Addr::Index(48), Addr::Index(4)),
+            Err(TextError::OutOfBounds(_))
+        ));
+        assert!(
+            dot.anchor_left(Addr::Index(1), Addr::Index(usize::MAX))
+                .is_err()
+        );
+        assert_eq!(buf.get(&dot).unwrap(), "Hello");
+    }
+
+    #[test]
+    fn test_dot_anchor_right() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.anchor_right(Addr::Index(5), Addr::Index(5)).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "Hello");
+        assert!(matches!(
+            dot.anchor_right(Addr::Index(4), Addr::Index(2)),
+            Err(TextError::OutOfBounds(_))
+        ));
+        assert!(dot.anchor_right(Addr::Index(1), Addr::Index(60)).is_err());
+        assert_eq!(buf.get(&dot).unwrap(), "Hello");
+    }
+
+    #[test]
+    fn test_dot_left_right() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(5), Addr::Index(10)).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), " ther");
+    }
+
+    #[test]
+    fn test_dot_text() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(6), Addr::LineEnd(1)).unwrap();
+        let text = dot.text().unwrap();
+        assert_eq!(text.slice().len_chars(), 22);
+        assert_eq!(text.slice().len_lines(), 3);
+        assert_eq!(text.slice().slice(..5), "there");
+        drop(text);
+
+        // reversed dot
+        dot.left_right(Addr::Index(6), Addr::Index(2)).unwrap();
+        assert!(dot.text().is_err());
+    }
+
+    #[test]
+    fn test_buffer_concurrent_readers() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let buf = buf.share();
+                std::thread::spawn(move || {
+                    for _ in 0..200 {
+                        let text = buf.to_string();
+                        assert!(text.starts_with("Hello there !\n"));
+                        assert_eq!(text.chars().count() % 2, 0);
+                        assert_eq!(buf.line(0).unwrap().slice(), "Hello there !\n");
+                    }
+                })
+            })
+            .collect();
+        let mut writer = buf.share();
+        let writer = std::thread::spawn(move || {
+            for _ in 0..200 {
+                writer.append("ab").unwrap();
+            }
+        });
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(buf.len_chars(), 450);
+    }
+
+    #[test]
+    fn test_shared_buffer() {
+        let shared = SharedBuffer::new(Buffer::from("|"));
+        let threads: Vec<_> = ["a", "b"]
+            .into_iter()
+            .map(|s| {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        let mut buf = shared.lock_write();
+                        buf.append(s).unwrap();
+                        buf.prepend(s).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let buf = shared.lock_read();
+        let text = buf.to_string();
+        assert_eq!(text.len(), 401);
+        assert_eq!(text.matches('a').count(), 200);
+        let (left, right) = text.split_once('|').unwrap();
+        assert_eq!(left.chars().rev().collect::<String>(), right);
+    }
+
+    #[test]
+    fn test_buffer_read_only() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        buf.insert(Addr::BufferStart, "> ").unwrap();
+        buf.set_read_only(true);
+        assert!(buf.is_read_only());
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(2), Addr::Index(7)).unwrap();
+        let ret = buf.set(&mut dot, RopeSlice::from("Bye"));
+        assert!(matches!(ret, Err(TextError::ReadOnly)));
+        assert!(matches!(buf.delete(&mut dot), Err(TextError::ReadOnly)));
+        assert!(matches!(buf.undo(), Err(TextError::ReadOnly)));
+        assert!(matches!(
+            buf.with_lock_mut(|text| text.remove(..)),
+            Err(TextError::ReadOnly)
+        ));
+        assert!(matches!(
+            buf.trim_trailing_whitespace(),
+            Err(TextError::ReadOnly)
+        ));
+        assert_eq!(buf.get(&dot).unwrap(), "Hello");
+        assert_eq!(buf.len_chars(), 52);
+
+        buf.set_read_only(false);
+        buf.undo().unwrap();
+        assert!(buf == Buffer::from_file(Path::new("tests/test.txt")).unwrap());
+    }
+
+    #[test]
+    fn test_buffer_poisoned_lock() {
+        let mut buf = Buffer::from("A minimal text editor.");
+        let text = Arc::clone(&buf.text);
+        let ret = std::thread::spawn(move || {
+            let _guard = text.write().unwrap();
+            panic!("poisoning the buffer");
+        })
+        .join();
+        assert!(ret.is_err());
+        assert!(buf.text.is_poisoned());
+
+        buf.insert(Addr::BufferStart, ">").unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(3), Addr::Index(10)).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "minimal");
+        assert_eq!(buf.len_chars(), 23);
+    }
+
+    #[test]
+    fn test_buffer_apply_edits() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let dot = |from, to| Dot::from_indices(&buf, from, to);
+        let edits = vec![
+            (dot(28, 29), String::from("We")),
+            (dot(0, 5), String::from("Hi")),
+            (dot(18, 21), String::from("is")),
+            (dot(13, 13), String::from("!!")),
+            (dot(13, 13), String::from("?")),
+        ];
+        buf.apply_edits(edits).unwrap();
+        assert_eq!(
+            buf.to_string(),
+            "Hi there !!!?\nHow is you ?\nWe test a text editor.\n"
+        );
+        buf.undo().unwrap();
+        assert!(buf == Buffer::from_file(Path::new("tests/test.txt")).unwrap());
+
+        let dot = |from, to| Dot::from_indices(&buf, from, to);
+        let edits = vec![
+            (dot(0, 5), String::from("Hi")),
+            (dot(14, 20), String::new()),
+            (dot(4, 8), String::from("x")),
+        ];
+        let ret = buf.apply_edits(edits);
+        assert!(matches!(ret, Err(TextError::InvalidAddr(_))));
+        let other = Buffer::from("other");
+        let ret = buf.apply_edits(vec![(Dot::new(&other), String::new())]);
+        assert!(matches!(ret, Err(TextError::InvalidAddr(_))));
+        assert!(buf == Buffer::from_file(Path::new("tests/test.txt")).unwrap());
+    }
+
+    #[test]
+    fn test_buffer_diff() {
+        let buf = Buffer::from("one\ntwo\nthree\nfour\nfive\nsix\n");
+        let other = Buffer::from("zero\none\n2\nthree\nfive\nsix\nseven");
+        let edits = buf.diff(&other);
+        let hunks: Vec<_> = edits
+            .iter()
+            .map(|(dot, s)| (buf.get(dot).unwrap(), s.as_str()))
+            .collect();
+        assert_eq!(
+            hunks,
+            [
+                (String::new(), "zero\n"),
+                (String::from("two\n"), "2\n"),
+                (String::from("four\n"), ""),
+                (String::new(), "seven"),
+            ]
+        );
+        let mut copy = buf.clone();
+        let edits = copy.diff(&other);
+        copy.apply_edits(edits).unwrap();
+        assert!(copy == other);
+
+        assert!(buf.diff(&buf.clone()).is_empty());
+        assert!(buf.diff(&buf.share()).is_empty());
+        let mut copy = buf.clone();
+        copy.apply_edits(copy.diff(&Buffer::new())).unwrap();
+        assert_eq!(copy.to_string(), "");
+
+        // the lines kept are a longest common subsequence
+        let mut seed = 7u32;
+        let mut lines = |n| {
+            (0..n)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    ["a\n", "b\n", "c\n"][(seed >> 16) as usize % 3]
+                })
+                .collect::<Vec<_>>()
+        };
+        for n in 0..40 {
+            let (a, b) = (lines(n % 9), lines(n % 7));
+            let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+            for i in (0..a.len()).rev() {
+                for j in (0..b.len()).rev() {
+                    lcs[i][j] = match a[i] == b[j] {
+                        true => lcs[i + 1][j + 1] + 1,
+                        false => lcs[i + 1][j].max(lcs[i][j + 1]),
+                    };
+                }
+            }
+            let mut buf = Buffer::from(a.concat().as_str());
+            let other = Buffer::from(b.concat().as_str());
+            let edits = buf.diff(&other);
+            let removed: usize = edits
+                .iter()
+                .map(|(dot, _)| dot.lines().unwrap().len())
+                .sum();
+            let empty = edits
+                .iter()
+                .filter(|(dot, _)| dot.is_empty().unwrap())
+                .count();
+            assert_eq!(a.len() - (removed - empty), lcs[0][0]);
+            buf.apply_edits(edits).unwrap();
+            assert!(buf == other);
+        }
+
+        // large inputs with scattered changes stay cheap
+        let a: String = (0..20_000).map(|i| format!("{i}\n")).collect();
+        let b: String = (0..20_000)
+            .map(|i| match i % 100 {
+                0 => format!("changed {i}\n"),
+                _ => format!("{i}\n"),
+            })
+            .collect();
+        let mut buf = Buffer::from(a.as_str());
+        let other = Buffer::from(b.as_str());
+        let edits = buf.diff(&other);
+        assert_eq!(edits.len(), 200);
+        buf.apply_edits(edits).unwrap();
+        assert!(buf == other);
+    }
+
+    #[test]
+    fn test_buffer_set_slice() {
+        let line = "the quick brown fox jumps over the lazy dog\n";
+        let src = Buffer::from(line.repeat(20_000).as_str());
+        let mut buf = Buffer::from("[]");
+        let mut dot = Dot::from_indices(&buf, 1, 1);
+        let text = src.text.read().unwrap();
+        let copied = text.slice(line.len()..text.len_chars() - line.len());
+        assert!(copied.chunks().count() > 1);
+        buf.set_slice(&mut dot, copied).unwrap();
+        assert!(buf.text.read().unwrap().slice(1..copied.len_chars() + 1) == copied);
+        assert_eq!(buf.text.read().unwrap().len_lines(), 19_999);
+        // history holds the slice as a rope, not a flattened copy
+        let inserted = buf.history.lock().unwrap().undo.last().unwrap()[0]
+            .inserted
+            .clone();
+        assert!(inserted.slice(..) == copied);
+        assert!(inserted.chunks().count() > 1);
+        drop(text);
+
+        buf.undo().unwrap().unwrap();
+        assert_eq!(buf.to_string(), "[]");
+
+        let mut dot = Dot::from_indices(&buf, 0, 1);
+        buf.set_str(&mut dot, "(").unwrap();
+        assert_eq!(buf.to_string(), "(]");
+    }
+
+    #[test]
+    fn test_buffer_insert_at_each() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dots = [
+            Dot::from_indices(&buf, 28, 28),
+            Dot::from_indices(&buf, 0, 0),
+            Dot::from_indices(&buf, 8, 14),
+        ];
+        buf.insert_at_each(&mut dots, "X").unwrap();
+        assert_eq!(
+            buf.to_string(),
+            "XHello there !\nXHow are you ?\nXI test a text editor.\n"
+        );
+        let at: Vec<_> = dots.iter().map(|d| d.indices().unwrap()).collect();
+        assert_eq!(at, [(31, 31), (1, 1), (16, 16)]);
+
+        // cursors together insert once
+        let mut dots = [Dot::from_indices(&buf, 1, 1), Dot::from_indices(&buf, 1, 1)];
+        buf.insert_at_each(&mut dots, "YZ").unwrap();
+        assert!(buf.to_string().starts_with("XYZHello"));
+        assert_eq!(dots[1].indices().unwrap(), (3, 3));
+
+        // nothing to type is no edit at all
+        let events = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::clone(&events);
+        buf.on_change(move |_| {
+            seen.fetch_add(1, atomic::Ordering::Relaxed);
+        });
+        buf.insert_at_each(&mut dots, "").unwrap();
+        assert_eq!(events.load(atomic::Ordering::Relaxed), 0);
+
+        buf.undo().unwrap().unwrap();
+        buf.undo().unwrap().unwrap();
+        assert_eq!(
+            buf.to_string(),
+            "Hello there !\nHow are you ?\nI test a text editor.\n"
+        );
+    }
+
+    #[test]
+    fn test_buffer_with_lock() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let (len, word) = buf.with_lock(|text| (text.len_chars(), text.slice(6..11).to_string()));
+        assert_eq!((len, word), (50, String::from("there")));
+
+        let mark = buf.add_mark(Addr::Index(30)).unwrap();
+        let removed = buf
+            .with_lock_mut(|text| {
+                text.remove(14..18);
+                text.insert(14, "Who ");
+                text.remove(0..6);
+                6
+            })
+            .unwrap();
+        assert_eq!(removed, 6);
+        assert_eq!(
+            buf.to_string(),
+            "there !\nWho are you ?\nI test a text editor.\n"
+        );
+        assert_eq!(buf.resolve(&mark), 24);
+        assert!(buf.is_dirty());
+        buf.with_lock_mut(|text| text.len_chars()).unwrap();
+        buf.undo().unwrap();
+        assert!(buf == Buffer::from_file(Path::new("tests/test.txt")).unwrap());
+
+        // the change is found across chunks, shared or rebuilt
+        let s = "aé€𝄞\n".repeat(2000);
+        let mut buf = Buffer::from(s.as_str());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&events);
+        buf.on_change(move |e| {
+            seen.lock()
+                .unwrap()
+                .push((e.at, e.removed, e.inserted.clone()))
+        });
+        buf.with_lock_mut(|text| text.insert(5003, "x")).unwrap();
+        buf.with_lock_mut(|text| text.remove(0..2)).unwrap();
+        buf.with_lock_mut(|text| text.remove(9997..9999)).unwrap();
+        buf.with_lock_mut(|text| *text = Rope::from(text.to_string()))
+            .unwrap();
+        let mut edited = Rope::from(s.as_str());
+        edited.insert(5003, "x");
+        edited.remove(0..2);
+        edited.remove(9997..9999);
+        assert!(read(&buf.text).clone() == edited);
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                (5003, 0, String::from("x")),
+                (0, 2, String::new()),
+                (9997, 2, String::new()),
+            ]
+        );
+
+        buf.set_read_only(true);
+        let ret = buf.with_lock_mut(|_| unreachable!());
+        assert!(matches!(ret, Err(TextError::ReadOnly)));
+    }
+
+    #[test]
+    fn test_buffer_slice_bytes() {
+        let buf = Buffer::from("né à Noël");
+        assert_eq!(buf.slice_bytes(0, 3).unwrap(), "né");
+        assert_eq!(buf.slice_bytes(4, 12).unwrap(), "à Noël");
+        assert_eq!(buf.slice_bytes(12, 12).unwrap(), "");
+        let ret = buf.slice_bytes(0, 2);
+        assert!(matches!(
+            ret,
+            Err(TextError::OutOfBounds(
+                ropey::Error::ByteRangeNotCharBoundary(..)
+            ))
+        ));
+        assert!(buf.slice_bytes(5, 6).is_err());
+        assert!(buf.slice_bytes(6, 4).is_err());
+        assert!(buf.slice_bytes(0, 13).is_err());
+    }
+
+    #[test]
+    fn test_char_indices() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let pairs: Vec<_> = buf.char_indices().take(5).collect();
+        assert_eq!(pairs, [(0, 'H'), (1, 'e'), (2, 'l'), (3, 'l'), (4, 'o')]);
+        assert_eq!(buf.char_indices().len(), 50);
+
+        // char indices, not bytes
+        let buf = Buffer::from("né à Noël");
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(3), Addr::Index(6)).unwrap();
+        let pairs: Vec<_> = dot.char_indices().unwrap().collect();
+        assert_eq!(pairs, [(3, 'à'), (4, ' '), (5, 'N')]);
+        dot.left_right(Addr::Index(6), Addr::Index(20)).unwrap();
+        assert!(dot.char_indices().is_err());
+
+        // across chunk boundaries
+        let s = "aé€𝄞\n".repeat(2000);
+        let buf = Buffer::from(s.as_str());
+        assert!(read(&buf.text).chunks().count() > 1);
+        assert!(buf.char_indices().eq(s.chars().enumerate()));
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(7), Addr::Index(9003)).unwrap();
+        let want = s.chars().enumerate().skip(7).take(8996);
+        assert!(dot.char_indices().unwrap().eq(want));
+    }
+
+    #[test]
+    fn test_dot_text_chars_rev() {
+        let buf = Buffer::from("né à Noël");
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(1), Addr::Index(8)).unwrap();
+        let text = dot.text().unwrap();
+        assert_eq!(text.chars_rev().collect::<String>(), "ëoN à é");
+        assert_eq!(text.chars_rev().find(|c| !c.is_alphabetic()), Some(' '));
+        drop(text);
+        dot.left_right(Addr::Index(3), Addr::Index(3)).unwrap();
+        assert_eq!(dot.text().unwrap().chars_rev().count(), 0);
+    }
+
+    #[test]
+    fn test_buffer_insert() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        buf.insert(Addr::Index(5), ",").unwrap();
+        buf.insert(Addr::LineStart(1), "Hi. ").unwrap();
+        buf.insert(Addr::BufferEnd, "Bye.").unwrap();
+        let dot = Dot::new(&buf);
+        assert_eq!(
+            buf.get(&dot).unwrap(),
+            "Hello, there !\nHi. How are you ?\nI test a text editor.\nBye."
+        );
+        assert!(buf.insert(Addr::Index(100), "x").is_err());
+    }
 
-    pub fn anchor_right(&mut self, from: Addr, anchor: Addr) -> ropey::Result<()> {
-        self.from = Addr::Index(anchor.as_index(self.text)? - from.as_index(self.text)?);
-        self.to = Addr::Index(anchor.as_index(self.text)?);
-        Ok(())
+    #[test]
+    fn test_buffer_delete() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(5), Addr::LineStart(1)).unwrap();
+        buf.delete(&mut dot).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "");
+        assert_eq!(dot.from.as_index(&buf.text).unwrap(), 5);
+        dot.left_right(Addr::BufferStart, Addr::LineEnd(0)).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "HelloHow are you ?\n");
     }
 
-    pub fn move_left(&mut self, n: usize) -> ropey::Result<()> {
-        self.from.move_left(self.text, n)?;
-        self.to.move_left(self.text, n)?;
-        Ok(())
+    #[test]
+    fn test_buffer_char_at() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        assert_eq!(buf.char_at(Addr::BufferStart).unwrap(), 'H');
+        assert_eq!(buf.char_at(Addr::Coordinates(1, 4)).unwrap(), 'a');
+        assert!(buf.char_at(Addr::BufferEnd).is_err());
+
+        assert_eq!(buf.char_before(Addr::LineStart(1)).unwrap(), '\n');
+        assert_eq!(buf.char_before(Addr::BufferEnd).unwrap(), '\n');
+        assert!(buf.char_before(Addr::BufferStart).is_err());
     }
 
-    pub fn move_right(&mut self, n: usize) -> ropey::Result<()> {
-        self.from.move_right(self.text, n)?;
-        self.to.move_right(self.text, n)?;
-        Ok(())
+    #[test]
+    fn test_buffer_undo_redo() {
+        let mut buf = Buffer::from_reader("Hello there".as_bytes()).unwrap();
+        let all = Dot::new(&buf);
+        buf.insert(Addr::Index(5), ",").unwrap();
+        assert_eq!(buf.get(&all).unwrap(), "Hello, there");
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(6), Addr::BufferEnd).unwrap();
+        buf.delete(&mut dot).unwrap();
+        assert_eq!(buf.get(&all).unwrap(), "Hello,");
+
+        let dot = buf.undo().unwrap().unwrap();
+        assert_eq!(buf.get(&all).unwrap(), "Hello, there");
+        assert_eq!(buf.get(&dot).unwrap(), " there");
+        let dot = buf.undo().unwrap().unwrap();
+        assert_eq!(buf.get(&all).unwrap(), "Hello there");
+        assert_eq!(buf.get(&dot).unwrap(), "");
+        assert!(buf.undo().unwrap().is_none());
+
+        let dot = buf.redo().unwrap().unwrap();
+        assert_eq!(buf.get(&all).unwrap(), "Hello, there");
+        assert_eq!(buf.get(&dot).unwrap(), ",");
+
+        // a new edit drops what is left to redo
+        buf.insert(Addr::BufferStart, "> ").unwrap();
+        assert!(buf.redo().unwrap().is_none());
+        assert_eq!(buf.get(&all).unwrap(), "> Hello, there");
     }
 
-    pub fn extend_left(&mut self, n: usize) -> ropey::Result<()> {
-        self.to.move_left(self.text, n)?;
-        Ok(())
+    #[test]
+    fn test_buffer_replace_all() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let all = Dot::new(&buf);
+        assert_eq!(buf.replace_all(r"\w+", "w").unwrap(), 10);
+        assert_eq!(buf.get(&all).unwrap(), "w w !\nw w w ?\nw w w w w.\n");
+        assert!(buf.is_dirty());
+
+        // a single undo step
+        buf.undo().unwrap();
+        assert_eq!(
+            buf.get(&all).unwrap(),
+            "Hello there !\nHow are you ?\nI test a text editor.\n"
+        );
+        assert_eq!(buf.replace_all("xyz", "w").unwrap(), 0);
+        assert!(buf.replace_all("(", "w").is_err());
     }
 
-    pub fn extend_right(&mut self, n: usize) -> ropey::Result<()> {
-        self.from.move_right(self.text, n)?;
-        Ok(())
+    #[test]
+    fn test_buffer_replace_all_captures() {
+        let mut buf = Buffer::from_reader("key=value\nname=big\n".as_bytes()).unwrap();
+        let all = Dot::new(&buf);
+        assert_eq!(buf.replace_all(r"(\w+)=(?<v>\w+)", "$v: $1 $$").unwrap(), 2);
+        assert_eq!(buf.get(&all).unwrap(), "value: key $\nbig: name $\n");
     }
 
-    pub fn trim_left(&mut self, n: usize) -> ropey::Result<()> {
-        self.to.move_right(self.text, n)?;
-        if self.to.as_index(self.text)? < self.from.as_index(self.text)? {
-            (self.from, self.to) = (self.to, self.from);
+    #[test]
+    fn test_buffer_transaction() {
+        let mut buf = Buffer::from_reader("Hello".as_bytes()).unwrap();
+        let all = Dot::new(&buf);
+        buf.begin_transaction();
+        for (i, c) in [" ", "t", "h", "e", "r"].iter().enumerate() {
+            buf.insert(Addr::Index(5 + i), c).unwrap();
         }
-        Ok(())
+        // nested transactions are part of the outer one
+        buf.begin_transaction();
+        buf.insert(Addr::BufferEnd, "e").unwrap();
+        buf.commit_transaction();
+        buf.commit_transaction();
+        assert_eq!(buf.get(&all).unwrap(), "Hello there");
+
+        let dot = buf.undo().unwrap().unwrap();
+        assert_eq!(buf.get(&all).unwrap(), "Hello");
+        assert_eq!(dot.from.as_index(&buf.text).unwrap(), 5);
+        assert!(buf.undo().unwrap().is_none());
+        buf.redo().unwrap().unwrap();
+        assert_eq!(buf.get(&all).unwrap(), "Hello there");
     }
 
-    pub fn trim_right(&mut self, n: usize) -> ropey::Result<()> {
-        self.from.move_left(self.text, n)?;
-        if self.from.as_index(self.text)? > self.to.as_index(self.text)? {
-            (self.from, self.to) = (self.to, self.from);
-        }
-        Ok(())
+    #[test]
+    fn test_buffer_undo_in_transaction() {
+        let mut buf = Buffer::from("abc");
+        buf.insert(Addr::Index(3), "X").unwrap();
+        buf.begin_transaction();
+        buf.insert(Addr::BufferStart, "YY").unwrap();
+        // the edits so far are undone as a step of their own
+        buf.undo().unwrap().unwrap();
+        assert_eq!(buf.to_string(), "abcX");
+        buf.insert(Addr::BufferStart, "Z").unwrap();
+        buf.commit_transaction();
+        buf.undo().unwrap().unwrap();
+        assert_eq!(buf.to_string(), "abcX");
+        buf.undo().unwrap().unwrap();
+        assert_eq!(buf.to_string(), "abc");
+        assert!(buf.undo().unwrap().is_none());
     }
-}
 
-#[derive(Clone)]
-pub struct Buffer {
-    text: Arc<Mutex<Rope>>,
-}
+    #[test]
+    fn test_dot_flip() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(10), Addr::Index(5)).unwrap();
+        dot.flip().unwrap();
+        assert_eq!(dot.from.as_index(&buf.text).unwrap(), 5);
+        assert_eq!(dot.to.as_index(&buf.text).unwrap(), 10);
+        assert_eq!(buf.get(&dot).unwrap(), " ther");
 
-impl Default for Buffer {
-    fn default() -> Self {
-        Self::new()
+        // already ordered
+        dot.flip().unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), " ther");
     }
-}
 
-impl Buffer {
-    pub fn new() -> Self {
-        Buffer {
-            text: Arc::new(Mutex::new(Rope::new())),
-        }
-    }
+    #[test]
+    fn test_dot_swap_ends() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(5), Addr::Index(10)).unwrap();
+        dot.swap_ends();
+        assert_eq!(dot.from.as_index(&buf.text).unwrap(), 10);
+        assert_eq!(dot.to.as_index(&buf.text).unwrap(), 5);
+        assert_eq!(buf.get(&dot).unwrap(), " ther");
 
-    pub fn from_reader<T>(reader: T) -> io::Result<Self>
-    where
-        T: io::Read,
-    {
-        match Rope::from_reader(reader) {
-            Ok(text) => Ok(Buffer {
-                text: Arc::new(Mutex::new(text)),
-            }),
-            Err(e) => Err(e),
-        }
+        // swapped back, where flip would have left it
+        dot.swap_ends();
+        assert_eq!(dot.from.as_index(&buf.text).unwrap(), 5);
+        assert_eq!(dot.to.as_index(&buf.text).unwrap(), 10);
     }
 
-    pub fn from_file(path: &Path) -> io::Result<Self> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        Self::from_reader(reader)
+    #[test]
+    fn test_dot_as_range() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        assert_eq!(dot.as_range().unwrap(), 0..50);
+        dot.left_right(Addr::Index(10), Addr::Index(5)).unwrap();
+        assert_eq!(dot.as_range().unwrap(), 5..10);
+        // the dot itself keeps its direction
+        assert_eq!(dot.from.as_index(&buf.text).unwrap(), 10);
     }
 
-    pub fn get(&self, dot: &Dot) -> ropey::Result<String> {
-        let from = dot.from.as_index(&self.text)?;
-        let to = dot.to.as_index(&self.text)?;
+    #[test]
+    fn test_dot_split_matches() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::BufferStart, Addr::Index(11)).unwrap();
+        let words: Vec<String> = dot
+            .split_matches(r"\w+")
+            .unwrap()
+            .iter()
+            .map(|d| buf.get(d).unwrap())
+            .collect();
+        assert_eq!(words, ["Hello", "there"]);
+
+        // matches are clamped to the dot and use buffer indices
+        dot.left_right(Addr::Index(8), Addr::Index(17)).unwrap();
+        let dots = dot.split_matches(r"\w+").unwrap();
+        let spans: Vec<(usize, usize)> = dots.iter().map(|d| d.indices().unwrap()).collect();
+        assert_eq!(spans, [(8, 11), (14, 17)]);
 
-        let slice: String = dot.text.lock().unwrap().slice(from..to).chars().collect();
-        Ok(slice)
+        // empty matches don't loop forever
+        dot.left_right(Addr::BufferStart, Addr::Index(3)).unwrap();
+        assert_eq!(dot.split_matches("x*").unwrap().len(), 4);
+        assert!(dot.split_matches("(").is_err());
     }
 
-    pub fn set(&mut self, dot: &mut Dot, s: RopeSlice) -> ropey::Result<()> {
-        let from = dot.from.as_index(&self.text)?;
-        let to = dot.to.as_index(&self.text)?;
+    #[test]
+    fn test_dot_split_between() {
+        let buf = Buffer::from_reader("a,b,c".as_bytes()).unwrap();
+        let dot = Dot::new(&buf);
+        let pieces: Vec<String> = dot
+            .split_between(",")
+            .unwrap()
+            .iter()
+            .map(|d| buf.get(d).unwrap())
+            .collect();
+        assert_eq!(pieces, ["a", "b", "c"]);
 
-        let mut text = dot.text.lock().unwrap();
-        text.remove(from..to);
-        text.insert(from, &s.to_string());
-        Ok(())
+        // delimiters at the edges give empty pieces
+        let buf = Buffer::from_reader(",a,,b,".as_bytes()).unwrap();
+        let dot = Dot::new(&buf);
+        let pieces: Vec<String> = dot
+            .split_between(",")
+            .unwrap()
+            .iter()
+            .map(|d| buf.get(d).unwrap())
+            .collect();
+        assert_eq!(pieces, ["", "a", "", "b", ""]);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_dot_lines() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(6), Addr::Coordinates(2, 6))
+            .unwrap();
+        let lines: Vec<String> = dot
+            .lines()
+            .unwrap()
+            .iter()
+            .map(|d| buf.get(d).unwrap())
+            .collect();
+        assert_eq!(lines, ["there !\n", "How are you ?\n", "I test"]);
+
+        // within a single line, and up to the start of the next one
+        dot.left_right(Addr::Index(6), Addr::LineEnd(0)).unwrap();
+        let lines = dot.lines().unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(buf.get(&lines[0]).unwrap(), "there !\n");
+
+        // empty dot
+        dot.left_right(Addr::Index(3), Addr::Index(3)).unwrap();
+        let lines = dot.lines().unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(buf.get(&lines[0]).unwrap(), "");
+    }
 
     #[test]
-    fn test_buffer_from_file() {
+    fn test_dot_line_dots() {
         let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
         let mut dot = Dot::new(&buf);
-        dot.left_right(Addr::BufferStart, Addr::BufferEnd).unwrap();
+        dot.left_right(Addr::Index(6), Addr::Coordinates(2, 6))
+            .unwrap();
+        let lines: Vec<(usize, String)> = dot
+            .line_dots()
+            .unwrap()
+            .iter()
+            .map(|(n, d)| (*n, buf.get(d).unwrap()))
+            .collect();
         assert_eq!(
-            buf.get(&dot).unwrap(),
-            String::from("Hello there !\nHow are you ?\nI test a text editor.")
+            lines,
+            [
+                (0, String::from("there !\n")),
+                (1, String::from("How are you ?\n")),
+                (2, String::from("I test")),
+            ]
         );
+
+        dot.left_right(Addr::Coordinates(1, 4), Addr::BufferEnd)
+            .unwrap();
+        let numbers: Vec<usize> = dot.line_dots().unwrap().iter().map(|(n, _)| *n).collect();
+        assert_eq!(numbers, [1, 2]);
     }
 
     #[test]
-    fn test_dot_anchor_left() {
-        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+    fn test_dot_replace() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
         let mut dot = Dot::new(&buf);
-        dot.anchor_left(Addr::BufferStart, Addr::Index(5)).unwrap();
-        assert_eq!(buf.get(&dot).unwrap(), "Hello");
+        dot.left_right(Addr::LineStart(1), Addr::LineEndBeforeNewline(1))
+            .unwrap();
+        assert_eq!(dot.replace(&mut buf, r"(\w)(\w*)", "$2${1}ay").unwrap(), 3);
+        assert_eq!(buf.get(&dot).unwrap(), "owHay reaay ouyay ?");
+        assert_eq!(
+            buf.get(&Dot::new(&buf)).unwrap(),
+            "Hello there !\nowHay reaay ouyay ?\nI test a text editor.\n"
+        );
+
+        // shrinking replacement
+        assert_eq!(dot.replace(&mut buf, r"\w+", "").unwrap(), 3);
+        assert_eq!(buf.get(&dot).unwrap(), "   ?");
+        assert_eq!(dot.replace(&mut buf, "xyz", "").unwrap(), 0);
+        assert_eq!(buf.get(&dot).unwrap(), "   ?");
     }
 
     #[test]
-    fn test_dot_anchor_right() {
+    fn test_selections_set_all() {
+        let mut buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut sel = Selections::new();
+        for (from, to) in [(18, 21), (0, 5), (14, 17)] {
+            let mut dot = Dot::new(&buf);
+            dot.left_right(Addr::Index(from), Addr::Index(to)).unwrap();
+            sel.add(dot).unwrap();
+        }
+        let words: Vec<String> = sel.iter().map(|d| buf.get(d).unwrap()).collect();
+        assert_eq!(words, ["Hello", "How", "are"]);
+
+        sel.set_all(&mut buf, "X").unwrap();
+        assert_eq!(
+            buf.get(&Dot::new(&buf)).unwrap(),
+            "X there !\nX X you ?\nI test a text editor.\n"
+        );
+        let spans: Vec<(usize, usize)> = sel.iter().map(bounds).collect();
+        assert_eq!(spans, [(0, 1), (10, 11), (12, 13)]);
+
+        // one undo step for the whole replacement
+        buf.undo().unwrap();
+        assert_eq!(
+            buf.get(&Dot::new(&buf)).unwrap(),
+            "Hello there !\nHow are you ?\nI test a text editor.\n"
+        );
+    }
+
+    #[test]
+    fn test_selections_merge() {
         let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
-        let mut dot = Dot::new(&buf);
-        dot.anchor_right(Addr::Index(5), Addr::Index(5)).unwrap();
-        assert_eq!(buf.get(&dot).unwrap(), "Hello");
+        let mut sel = Selections::new();
+        for (from, to) in [(3, 8), (0, 5), (8, 10), (20, 25)] {
+            let mut dot = Dot::new(&buf);
+            dot.left_right(Addr::Index(from), Addr::Index(to)).unwrap();
+            sel.add(dot).unwrap();
+        }
+        sel.merge();
+        let spans: Vec<(usize, usize)> = sel.iter().map(bounds).collect();
+        assert_eq!(spans, [(0, 8), (8, 10), (20, 25)]);
     }
 
     #[test]
-    fn test_dot_left_right() {
+    fn test_addr_relative() {
         let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
         let mut dot = Dot::new(&buf);
-        dot.left_right(Addr::Index(5), Addr::Index(10)).unwrap();
-        assert_eq!(buf.get(&dot).unwrap(), " ther");
+        dot.left_right(Addr::Index(6), Addr::Index(11)).unwrap();
+        dot.left_right(Addr::Relative(-6), Addr::Relative(2))
+            .unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "Hello there !");
+
+        dot.move_to_relative(5).unwrap();
+        assert_eq!(dot.indices().unwrap(), (18, 18));
+        dot.move_to_relative(-4).unwrap();
+        assert_eq!(dot.indices().unwrap(), (14, 14));
+        assert_eq!(Addr::Relative(3).as_index(&buf.text).unwrap(), 3);
+
+        // out of buffer cases leave the dot alone
+        assert!(dot.move_to_relative(-15).is_err());
+        assert!(dot.move_to_relative(37).is_err());
+        assert!(dot.move_to_relative(isize::MAX).is_err());
+        assert_eq!(dot.indices().unwrap(), (14, 14));
+        dot.move_to_relative(36).unwrap();
+        assert_eq!(dot.indices().unwrap(), (50, 50));
     }
 
     #[test]
@@ -268,10 +5552,11 @@ mod tests {
         let ret = dot.move_left(1);
         println!("{:?}", ret);
         assert!(ret.is_err());
+        assert_eq!(dot.indices().unwrap(), (0, 2));
         // regular case
-        dot.anchor_right(Addr::Index(7), Addr::BufferEnd).unwrap();
+        dot.anchor_right(Addr::Index(8), Addr::BufferEnd).unwrap();
         dot.move_left(1).unwrap();
-        assert_eq!(buf.get(&dot).unwrap(), " editor");
+        assert_eq!(buf.get(&dot).unwrap(), " editor.");
     }
 
     #[test]
@@ -283,27 +5568,512 @@ mod tests {
         dot.anchor_right(Addr::Index(1), Addr::BufferEnd).unwrap();
         let ret = dot.move_right(1);
         assert!(ret.is_err());
+        assert_eq!(dot.indices().unwrap(), (49, 50));
         // regular case
         dot.anchor_left(Addr::BufferStart, Addr::Index(5)).unwrap();
         dot.move_right(2).unwrap();
         assert_eq!(buf.get(&dot).unwrap(), "llo t");
+
+        // a failed move leaves neither end moved
+        let buf = Buffer::from("hello");
+        let mut dot = Dot::from_indices(&buf, 1, 5);
+        assert!(dot.move_right(1).is_err());
+        assert_eq!(dot.indices().unwrap(), (1, 5));
+    }
+
+    #[test]
+    fn test_addr_regex() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(
+            Addr::Regex(String::from("are")),
+            Addr::Regex(String::from(r"\?")),
+        )
+        .unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "are you ");
+        assert_eq!(
+            Addr::Regex(String::from("t[a-z]+r"))
+                .as_coordinates(&buf.text)
+                .unwrap(),
+            (0, 6)
+        );
+
+        // invalid pattern and no match cases
+        let ret = dot.left_right(Addr::Regex(String::from("(")), Addr::BufferEnd);
+        assert!(matches!(ret, Err(TextError::Regex(_))));
+        let ret = dot.left_right(Addr::Regex(String::from("xyz")), Addr::BufferEnd);
+        assert!(matches!(ret, Err(TextError::NoMatch(_))));
+    }
+
+    #[test]
+    fn test_addr_regex_backward() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::from_indices(&buf, 50, 50);
+        dot.left_right(Addr::RegexBackward(String::from("te")), Addr::BufferEnd)
+            .unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "text editor.\n");
+
+        // searches back from the start of dot
+        let mut dot = Dot::from_indices(&buf, 28, 28);
+        dot.left_right(Addr::RegexBackward(String::from("How")), Addr::Index(28))
+            .unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "How are you ?\n");
+        dot.left_right(Addr::RegexBackward(String::from("e")), Addr::Index(28))
+            .unwrap();
+        assert_eq!(dot.indices().unwrap(), (10, 28));
+        let ret = dot.left_right(Addr::RegexBackward(String::from("you")), Addr::Index(28));
+        assert!(matches!(ret, Err(TextError::NoMatch(_))));
+        assert_eq!(
+            Addr::RegexBackward(String::from("e"))
+                .as_coordinates(&buf.text)
+                .unwrap(),
+            (2, 14)
+        );
+        assert_eq!(
+            regex_index_before(&buf.text.read().unwrap(), "e", 13).unwrap(),
+            10
+        );
+
+        let ret = dot.left_right(Addr::RegexBackward(String::from("xyz")), Addr::BufferEnd);
+        assert!(matches!(ret, Err(TextError::NoMatch(_))));
+        let ret = regex_index_before(&buf.text.read().unwrap(), "How", 14);
+        assert!(matches!(ret, Err(TextError::NoMatch(_))));
+    }
+
+    #[test]
+    fn test_dot_move_grapheme() {
+        // e + combining acute, a regional indicator pair, then a plain char
+        let buf = Buffer::from_reader("e\u{301}\u{1F1EB}\u{1F1F7}x".as_bytes()).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::BufferStart, Addr::BufferStart)
+            .unwrap();
+        dot.move_right_grapheme(1).unwrap();
+        assert_eq!(dot.indices().unwrap(), (2, 2));
+        dot.move_right_grapheme(1).unwrap();
+        assert_eq!(dot.indices().unwrap(), (4, 4));
+        dot.move_right_grapheme(1).unwrap();
+        assert_eq!(dot.indices().unwrap(), (5, 5));
+        assert!(dot.move_right_grapheme(1).is_err());
+
+        dot.move_left_grapheme(2).unwrap();
+        assert_eq!(dot.indices().unwrap(), (2, 2));
+
+        // both ends move, and nothing moves when one end can't
+        dot.left_right(Addr::BufferStart, Addr::Index(2)).unwrap();
+        dot.move_right_grapheme(1).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "\u{1F1EB}\u{1F1F7}");
+        assert!(dot.move_left_grapheme(2).is_err());
+        assert_eq!(dot.indices().unwrap(), (2, 4));
+    }
+
+    #[test]
+    fn test_buffer_get_clamped() {
+        let buf = Buffer::from_reader("A minimal text editor.".as_bytes()).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(9), Addr::Index(2)).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "minimal");
+        assert!(buf.try_get_strict(&dot).is_err());
+
+        dot.left_right(Addr::Index(15), Addr::Index(100)).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "editor.");
+        assert!(buf.try_get_strict(&dot).is_err());
+
+        dot.left_right(Addr::Index(50), Addr::Index(100)).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "");
+
+        dot.left_right(Addr::Index(2), Addr::Index(9)).unwrap();
+        assert_eq!(buf.try_get_strict(&dot).unwrap(), "minimal");
+    }
+
+    #[test]
+    fn test_dot_sticky() {
+        let mut buf = Buffer::from_reader("A minimal text editor.".as_bytes()).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(10), Addr::Index(14)).unwrap();
+        dot.sticky(true).unwrap();
+        let mut stale = Dot::new(&buf);
+        stale.left_right(Addr::Index(10), Addr::Index(14)).unwrap();
+
+        buf.insert(Addr::BufferStart, "> ").unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "text");
+        assert_eq!(buf.get(&stale).unwrap(), "l te");
+
+        let mut word = Dot::new(&buf);
+        word.left_right(Addr::Index(4), Addr::Index(11)).unwrap();
+        buf.set(&mut word, RopeSlice::from("tiny")).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "text");
+        assert_eq!(dot.indices().unwrap(), (9, 13));
+
+        // a copy moves on its own
+        let mut copy = dot.clone();
+        copy.move_right(1).unwrap();
+        assert_eq!(buf.get(&copy).unwrap(), "ext ");
+        assert_eq!(buf.get(&dot).unwrap(), "text");
+
+        dot.sticky(false).unwrap();
+        buf.insert(Addr::BufferStart, "> ").unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "y te");
+        assert_eq!(buf.get(&copy).unwrap(), "ext ");
+    }
+
+    #[test]
+    fn test_dot_contains_overlaps() {
+        let buf = Buffer::from_reader("A minimal text editor.".as_bytes()).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(2), Addr::Index(9)).unwrap();
+        assert!(dot.contains(Addr::Index(5)).unwrap());
+        assert!(dot.contains(Addr::Index(2)).unwrap());
+        assert!(!dot.contains(Addr::Index(9)).unwrap());
+        assert!(!dot.contains(Addr::Index(12)).unwrap());
+
+        let mut other = Dot::new(&buf);
+        other.left_right(Addr::Index(8), Addr::Index(14)).unwrap();
+        assert!(dot.overlaps(&other).unwrap());
+        assert!(other.overlaps(&dot).unwrap());
+        other.left_right(Addr::Index(9), Addr::Index(14)).unwrap();
+        assert!(!dot.overlaps(&other).unwrap());
+
+        dot.left_right(Addr::Index(10), Addr::Index(10)).unwrap();
+        assert!(!dot.contains(Addr::Index(10)).unwrap());
+        assert!(!dot.overlaps(&other).unwrap());
+
+        let elsewhere = Dot::new(&buf.snapshot());
+        assert!(dot.overlaps(&elsewhere).is_err());
+    }
+
+    #[test]
+    fn test_dot_debug() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        assert_eq!(
+            format!("{dot:?}"),
+            "Dot { from: BufferStart, to: BufferEnd }"
+        );
+        assert_eq!(dot.debug_resolved(), "Dot(0..50)");
+        dot.left_right(Addr::Index(3), Addr::Index(80)).unwrap();
+        assert_eq!(format!("{dot:?}"), "Dot { from: Index(3), to: Index(80) }");
+        assert!(
+            dot.debug_resolved()
+                .starts_with("Dot(Index(3)..Index(80): ")
+        );
+    }
+
+    #[test]
+    fn test_dot_len() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(3), Addr::Index(3)).unwrap();
+        assert!(dot.is_empty().unwrap());
+        assert_eq!(dot.len().unwrap(), 0);
+
+        dot.left_right(Addr::Index(6), Addr::Index(11)).unwrap();
+        assert!(!dot.is_empty().unwrap());
+        assert_eq!(dot.len().unwrap(), 5);
+        dot.flip().unwrap();
+        assert_eq!(dot.len().unwrap(), 5);
+
+        dot.left_right(Addr::Index(6), Addr::Index(500)).unwrap();
+        assert!(dot.len().is_err());
+    }
+
+    #[test]
+    fn test_dot_move_clamped() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::BufferStart, Addr::Index(3)).unwrap();
+        assert_eq!(dot.move_left_clamped(2).unwrap(), 0);
+        assert_eq!(buf.get(&dot).unwrap(), "Hel");
+
+        assert_eq!(dot.move_right_clamped(2).unwrap(), 2);
+        assert_eq!(buf.get(&dot).unwrap(), "llo");
+        assert_eq!(dot.move_left_clamped(5).unwrap(), 2);
+        assert_eq!(buf.get(&dot).unwrap(), "Hel");
+
+        let len = buf.len_chars();
+        dot.left_right(Addr::Index(len - 2), Addr::Index(len - 1))
+            .unwrap();
+        assert_eq!(dot.move_right_clamped(5).unwrap(), 1);
+        assert_eq!(dot.indices().unwrap(), (len - 1, len));
+    }
+
+    #[test]
+    fn test_dot_move_word() {
+        let buf = Buffer::from_reader("Hello there ! How".as_bytes()).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::BufferStart, Addr::BufferStart)
+            .unwrap();
+        let mut starts = Vec::new();
+        for _ in 0..4 {
+            dot.move_word_right(1).unwrap();
+            starts.push(dot.indices().unwrap().0);
+        }
+        assert_eq!(starts, [6, 14, 17, 17]);
+
+        let mut starts = Vec::new();
+        for _ in 0..4 {
+            dot.move_word_left(1).unwrap();
+            starts.push(dot.indices().unwrap().0);
+        }
+        assert_eq!(starts, [14, 6, 0, 0]);
+
+        // from inside a word, and several words at once
+        dot.left_right(Addr::Index(2), Addr::Index(8)).unwrap();
+        dot.move_word_left(1).unwrap();
+        assert_eq!(dot.indices().unwrap(), (0, 0));
+        dot.move_word_right(2).unwrap();
+        assert_eq!(dot.indices().unwrap(), (14, 14));
+
+        // Unicode word boundaries keep "l'été" whole
+        let buf = Buffer::from("l'été dernier, ça\nva");
+        let mut dot = Dot::from_indices(&buf, 0, 0);
+        let mut starts = Vec::new();
+        for _ in 0..4 {
+            dot.move_word_right(1).unwrap();
+            starts.push(dot.indices().unwrap().0);
+        }
+        assert_eq!(starts, [6, 15, 18, 20]);
+        let mut starts = Vec::new();
+        for _ in 0..4 {
+            dot.move_word_left(1).unwrap();
+            starts.push(dot.indices().unwrap().0);
+        }
+        assert_eq!(starts, [18, 15, 6, 0]);
+    }
+
+    #[test]
+    fn test_dot_select_word_at() {
+        let buf = Buffer::from_reader("Hello there !  How".as_bytes()).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.select_word_at(Addr::Index(8)).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "there");
+        dot.select_word_at(Addr::Index(6)).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "there");
+        dot.select_word_at(Addr::Index(5)).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), " ");
+        dot.select_word_at(Addr::Index(14)).unwrap();
+        assert_eq!(dot.indices().unwrap(), (13, 15));
+        dot.select_word_at(Addr::Index(12)).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "!");
+        dot.select_word_at(Addr::BufferEnd).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "How");
+        assert!(dot.select_word_at(Addr::Index(40)).is_err());
+
+        let empty = Buffer::new();
+        let mut dot = Dot::new(&empty);
+        dot.select_word_at(Addr::BufferStart).unwrap();
+        assert!(dot.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_buffer_sort_lines() {
+        let mut buf = Buffer::from("head\npear\nApple\nfig\ntail");
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(7), Addr::LineStart(4)).unwrap();
+        buf.sort_lines(&dot, false).unwrap();
+        assert_eq!(buf.to_string(), "head\nApple\nfig\npear\ntail");
+        buf.sort_lines(&dot, true).unwrap();
+        assert_eq!(buf.to_string(), "head\npear\nfig\nApple\ntail");
+        buf.sort_lines_by(&dot, |a, b| a.to_lowercase().cmp(&b.to_lowercase()))
+            .unwrap();
+        assert_eq!(buf.to_string(), "head\nApple\nfig\npear\ntail");
+
+        let mut buf = Buffer::from("10\n9\n100");
+        let dot = Dot::new(&buf);
+        buf.sort_lines_by(&dot, |a, b| {
+            a.parse::<u32>().unwrap().cmp(&b.parse().unwrap())
+        })
+        .unwrap();
+        assert_eq!(buf.to_string(), "9\n10\n100");
+        buf.undo().unwrap();
+        assert_eq!(buf.to_string(), "10\n9\n100");
+
+        // line breaks stay in place, lone CRs included
+        let mut buf = Buffer::from("b\ra\r\nc\r");
+        buf.sort_lines(&Dot::new(&buf), false).unwrap();
+        assert_eq!(buf.to_string(), "a\rb\r\nc\r");
+    }
+
+    #[test]
+    fn test_buffer_unique_lines() {
+        let mut buf = Buffer::from("b\na\nb\na\na\nb\nb\nc\nc");
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::LineStart(1), Addr::BufferEnd).unwrap();
+        buf.sort_lines(&dot, false).unwrap();
+        assert_eq!(buf.unique_lines(&dot).unwrap(), 5);
+        assert_eq!(buf.to_string(), "b\na\nb\nc");
+        assert_eq!(buf.unique_lines(&Dot::new(&buf)).unwrap(), 0);
+
+        let mut buf = Buffer::from("x\nx\r\ny\ny\nz");
+        assert_eq!(buf.unique_lines(&Dot::new(&buf)).unwrap(), 2);
+        assert_eq!(buf.to_string(), "x\r\ny\nz");
+    }
+
+    #[test]
+    fn test_buffer_matching_bracket() {
+        let buf = Buffer::from("fn f(a: [u8; (1)]) { g(a[0]) }");
+        let at = |idx| buf.matching_bracket(Addr::Index(idx)).unwrap();
+        assert_eq!(at(4), Some(17));
+        assert_eq!(at(17), Some(4));
+        assert_eq!(at(8), Some(16));
+        assert_eq!(at(13), Some(15));
+        assert_eq!(at(19), Some(29));
+        assert_eq!(at(29), Some(19));
+        assert_eq!(at(0), None);
+        assert_eq!(at(30), None);
+        assert!(buf.matching_bracket(Addr::Index(31)).is_err());
+
+        let buf = Buffer::from("((a) ]");
+        assert_eq!(buf.matching_bracket(Addr::Index(0)).unwrap(), None);
+        assert_eq!(buf.matching_bracket(Addr::Index(1)).unwrap(), Some(3));
+        assert_eq!(buf.matching_bracket(Addr::Index(5)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_dot_expand_to() {
+        let buf = Buffer::from(r#"f(a, g(b, "c d"), (e)) + h(i)"#);
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(7), Addr::Index(8)).unwrap();
+        assert!(dot.expand_to(r"\(", r"\)").unwrap());
+        assert_eq!(buf.get(&dot).unwrap(), r#"(b, "c d")"#);
+        assert!(dot.expand_to(r"\(", r"\)").unwrap());
+        assert_eq!(buf.get(&dot).unwrap(), r#"(a, g(b, "c d"), (e))"#);
+        assert!(!dot.expand_to(r"\(", r"\)").unwrap());
+        assert_eq!(buf.get(&dot).unwrap(), r#"(a, g(b, "c d"), (e))"#);
+
+        dot.left_right(Addr::Index(12), Addr::Index(13)).unwrap();
+        assert!(dot.expand_to("\"", "\"").unwrap());
+        assert_eq!(buf.get(&dot).unwrap(), r#""c d""#);
+        dot.left_right(Addr::Index(27), Addr::Index(27)).unwrap();
+        assert!(!dot.expand_to("\"", "\"").unwrap());
+        assert!(dot.expand_to("[(]", "[)]").unwrap());
+        assert_eq!(buf.get(&dot).unwrap(), "(i)");
+    }
+
+    #[test]
+    fn test_dot_move_up_down() {
+        let mut buf = Buffer::from("\tlong line here\nshort\n\tanother long one\n");
+        assert_eq!(buf.tab_width(), 8);
+        buf.set_tab_width(4);
+        let mut dot = Dot::new(&buf);
+        let at = |dot: &Dot| dot.indices_in(&buf.text.read().unwrap()).unwrap().0;
+        dot.left_right(Addr::Index(6), Addr::Index(6)).unwrap();
+        assert_eq!(buf.visual_column(Addr::Index(6), 4).unwrap(), 9);
+
+        dot.move_down().unwrap();
+        assert_eq!(at(&dot), 21);
+        dot.move_down().unwrap();
+        assert_eq!(at(&dot), 28);
+        assert_eq!(buf.visual_column(Addr::Index(28), 4).unwrap(), 9);
+        dot.move_down().unwrap();
+        assert_eq!(at(&dot), 28);
+
+        dot.move_up().unwrap();
+        assert_eq!(at(&dot), 21);
+        dot.move_up().unwrap();
+        assert_eq!(at(&dot), 6);
+        dot.move_up().unwrap();
+        assert_eq!(at(&dot), 6);
+
+        // a horizontal move sets a new goal
+        dot.move_right(1).unwrap();
+        dot.move_down().unwrap();
+        assert_eq!(at(&dot), 21);
+        dot.move_down().unwrap();
+        assert_eq!(at(&dot), 29);
+
+        // within a tab
+        dot.left_right(Addr::Index(18), Addr::Index(18)).unwrap();
+        dot.move_down().unwrap();
+        assert_eq!(at(&dot), 22);
+        dot.move_up().unwrap();
+        assert_eq!(at(&dot), 18);
+    }
+
+    #[test]
+    fn test_dot_trim_whitespace() {
+        let buf = Buffer::from("  fn main() {\n\t}\n\n   \n");
+        let mut dot = Dot::new(&buf);
+        dot.trim_whitespace().unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "fn main() {\n\t}");
+
+        dot.left_right(Addr::Index(0), Addr::Index(5)).unwrap();
+        dot.trim_whitespace().unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "fn");
+
+        dot.left_right(Addr::Index(17), Addr::Index(22)).unwrap();
+        dot.trim_whitespace().unwrap();
+        assert_eq!(dot.indices_in(&buf.text.read().unwrap()).unwrap(), (17, 17));
+    }
+
+    #[test]
+    fn test_dot_round_to_lines() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.left_right(Addr::Index(6), Addr::Index(18)).unwrap();
+        dot.round_to_lines().unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "Hello there !\nHow are you ?\n");
+
+        dot.left_right(Addr::Index(20), Addr::LineStart(2)).unwrap();
+        dot.round_to_lines().unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "How are you ?\n");
+
+        dot.left_right(Addr::Index(31), Addr::Index(31)).unwrap();
+        dot.round_to_lines().unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "I test a text editor.\n");
+        dot.left_right(Addr::BufferEnd, Addr::BufferEnd).unwrap();
+        dot.round_to_lines().unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "");
+    }
+
+    #[test]
+    fn test_dot_select_line() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.select_line(1).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "How are you ?\n");
+        dot.select_line(2).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "I test a text editor.\n");
+        dot.select_line(3).unwrap();
+        assert!(dot.is_empty().unwrap());
+        assert!(dot.select_line(4).is_err());
+
+        dot.select_lines(0, 2).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "Hello there !\nHow are you ?\n");
+        assert!(dot.select_lines(2, 2).is_err());
+
+        let buf = Buffer::from("no newline");
+        let mut dot = Dot::new(&buf);
+        dot.select_line(0).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "no newline");
+    }
+
+    #[test]
+    fn test_dot_extend_left() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.anchor_right(Addr::Index(7), Addr::LineEndBeforeNewline(2))
+            .unwrap();
+        dot.extend_left(2).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "t editor.");
+
+        // out of buffer case
+        dot.anchor_left(Addr::BufferStart, Addr::Index(2)).unwrap();
+        assert!(dot.extend_left(1).is_err());
+        assert_eq!(buf.get(&dot).unwrap(), "He");
     }
 
-    // #[test]
-    // fn test_dot_extend_left() {
-    //     let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
-    //     let mut dot = Dot::new(&buf);
-    //     dot.anchor_right(Addr::Index(7), Addr::BufferEnd).unwrap();
-    //     dot.extend_left(2).unwrap();
-    //     assert_eq!(buf.get(&dot).unwrap(), "t editor.");
-    // }
-
-    // #[test]
-    // fn test_dot_extend_right() {
-    //     let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
-    //     let mut dot = Dot::new(&buf);
-    //     dot.anchor_right(Addr::Index(7), Addr::BufferEnd).unwrap();
-    //     dot.move_right(22).unwrap();
-    //     assert_eq!(buf.get(&dot).unwrap(), "e you ?");
-    // }
+    #[test]
+    fn test_dot_extend_right() {
+        let buf = Buffer::from_file(Path::new("tests/test.txt")).unwrap();
+        let mut dot = Dot::new(&buf);
+        dot.anchor_left(Addr::Coordinates(1, 6), Addr::Index(1))
+            .unwrap();
+        dot.extend_right(6).unwrap();
+        assert_eq!(buf.get(&dot).unwrap(), "e you ?");
+
+        // out of buffer case
+        dot.anchor_right(Addr::Index(2), Addr::BufferEnd).unwrap();
+        assert!(dot.extend_right(1).is_err());
+        assert_eq!(buf.get(&dot).unwrap(), ".\n");
+    }
 }